@@ -0,0 +1,116 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::utils::Ipv4Net;
+use serde_derive::Deserialize;
+use std::convert::TryFrom;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+/// Two networks whose clients may talk to each other, written `A:B`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Rule {
+    a: Ipv4Net,
+    b: Ipv4Net,
+}
+
+impl Rule {
+    fn permits(&self, src: Ipv4Addr, dst: Ipv4Addr) -> bool {
+        (self.a.contains(src) && self.b.contains(dst))
+            || (self.b.contains(src) && self.a.contains(dst))
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Rule, String> {
+        let i = s
+            .find(':')
+            .ok_or_else(|| format!("{}: expected two networks separated by ':'", s))?;
+        Ok(Rule {
+            a: s[..i].parse()?,
+            b: s[i + 1..].parse()?,
+        })
+    }
+}
+
+impl TryFrom<String> for Rule {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Rule, String> {
+        s.parse()
+    }
+}
+
+/// How the server treats packets from one client to another.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Policy {
+    /// Hand them to the kernel through the TUN device, subject to the host's
+    /// forwarding and firewall settings.
+    #[default]
+    Kernel,
+    Allow,
+    Deny,
+    /// Only between networks covered by a rule, in either direction.
+    Acl(Vec<Rule>),
+}
+
+impl Policy {
+    pub fn new(mode: &str, rules: Vec<Rule>) -> Result<Policy, String> {
+        match mode {
+            "kernel" => Ok(Policy::Kernel),
+            "allow" => Ok(Policy::Allow),
+            "deny" => Ok(Policy::Deny),
+            "acl" => Ok(Policy::Acl(rules)),
+            _ => Err(format!("unknown client-to-client policy: {}", mode)),
+        }
+    }
+
+    pub fn permits(&self, src: Ipv4Addr, dst: Ipv4Addr) -> bool {
+        match *self {
+            Policy::Kernel | Policy::Allow => true,
+            Policy::Deny => false,
+            Policy::Acl(ref rules) => rules.iter().any(|rule| rule.permits(src, dst)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::acl::*;
+
+    #[test]
+    fn rule_test() {
+        let rule: Rule = "10.10.10.100:192.168.5.0/24".parse().unwrap();
+        assert!(rule.permits(Ipv4Addr::new(10, 10, 10, 100), Ipv4Addr::new(192, 168, 5, 1)));
+        assert!(rule.permits(Ipv4Addr::new(192, 168, 5, 1), Ipv4Addr::new(10, 10, 10, 100)));
+        assert!(!rule.permits(Ipv4Addr::new(10, 10, 10, 101), Ipv4Addr::new(192, 168, 5, 1)));
+        assert!("10.10.10.100".parse::<Rule>().is_err());
+        assert!("10.10.10.100:300.0.0.0/8".parse::<Rule>().is_err());
+    }
+
+    #[test]
+    fn policy_test() {
+        let a = Ipv4Addr::new(10, 10, 10, 2);
+        let b = Ipv4Addr::new(10, 10, 10, 3);
+        assert!(Policy::new("allow", Vec::new()).unwrap().permits(a, b));
+        assert!(!Policy::new("deny", Vec::new()).unwrap().permits(a, b));
+        assert!(!Policy::new("acl", Vec::new()).unwrap().permits(a, b));
+        let rules = vec!["10.10.10.2:10.10.10.3".parse().unwrap()];
+        assert!(Policy::new("acl", rules).unwrap().permits(b, a));
+        assert!(Policy::new("open", Vec::new()).is_err());
+    }
+}
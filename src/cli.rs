@@ -1,68 +1,433 @@
+use crate::acl;
+use crate::config;
+use crate::control;
+use crate::device;
+use crate::dns;
+use crate::privilege;
+use crate::quota;
+use crate::ratelimit;
+use crate::utils;
 use clap;
-use clap::{App, Arg, SubCommand};
-use std::net::{IpAddr, Ipv4Addr};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use std::env;
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::str::FromStr;
+use zeroize::Zeroizing;
+
+const KEY_ENV: &str = "KYTAN_KEY";
 
 
 #[derive(Debug, Clone)]
 pub struct Server {
-    pub bind_addr: String,
+    pub bind_addr: IpAddr,
     pub port: u16,
-    pub key: String,
-    pub dns: IpAddr,
+    pub key: Zeroizing<String>,
+    pub dns: dns::Settings,
+    pub routing: utils::Routing,
+    pub public_addr: Option<IpAddr>,
+    pub sandbox: privilege::Sandbox,
+    pub peers: Vec<config::Peer>,
+    pub control: String,
+    pub metrics: Option<SocketAddr>,
+    pub quota: quota::Quota,
+    /// Where traffic is saved so that quotas survive restarts.
+    pub quota_state: String,
+    pub rate: ratelimit::Limits,
+    pub on_connect: Option<String>,
+    pub on_disconnect: Option<String>,
+    pub client_to_client: acl::Policy,
+    /// Bridge Ethernet frames instead of routing IP packets.
+    pub tap: bool,
+    pub interface: Option<String>,
+    pub mtu: u32,
 }
 
 #[derive(Debug, Clone)]
 pub struct Client {
     pub remote_addr: String,
     pub port: u16,
-    pub key: String,
+    pub key: Zeroizing<String>,
+    pub name: String,
     pub default_route: bool,
+    pub routes: Vec<utils::Ipv4Net>,
+    /// Networks that are always reached without the tunnel.
+    pub excludes: Vec<utils::Ipv4Net>,
+    /// Networks behind this client that the server should route to it.
+    pub subnets: Vec<utils::Ipv4Net>,
+    /// Route through a table of this number instead of replacing the default
+    /// route, marking kytan's own packets with it.
+    pub fwmark: Option<u32>,
+    /// Block traffic outside the tunnel while connected.
+    pub kill_switch: bool,
+    pub tap: bool,
+    pub interface: Option<String>,
+    /// The largest MTU to agree on with the server.
+    pub mtu: u32,
+    /// Lower the MTU to what the path to the server carries.
+    pub pmtu_discovery: bool,
+    pub dns_backend: dns::Backend,
+    pub sandbox: privilege::Sandbox,
+    pub metrics: Option<SocketAddr>,
+    pub up: Option<String>,
+    pub down: Option<String>,
 }
 
 #[derive(Debug, Clone)]
+pub struct Status {
+    pub control: String,
+    pub json: bool,
+}
+
+/// A request sent to a running server, e.g. by `kytan kick`.
+#[derive(Debug)]
+pub struct Command {
+    pub control: String,
+    pub request: control::Request,
+}
+
+#[derive(Debug)]
 pub enum Args {
     Client(Client),
     Server(Server),
+    Status(Status),
+    Usage(Status),
+    Command(Command),
+    Unblock,
+    Cleanup { force: bool },
 }
 
-pub fn get_args() -> Result<Args, String> {
-    let matches = App::new("kytan: High Performance Peer-to-Peer VPN")
+fn sandbox_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("user")
+            .long("user")
+            .help("switch to this user once the tunnel is set up")
+            .takes_value(true),
+        Arg::with_name("group")
+            .long("group")
+            .help("switch to this group once the tunnel is set up")
+            .takes_value(true),
+        Arg::with_name("chroot")
+            .long("chroot")
+            .help("chroot into this directory once the tunnel is set up")
+            .takes_value(true),
+        Arg::with_name("seccomp")
+            .long("seccomp")
+            .help("deny unneeded syscalls once the tunnel is set up"),
+    ]
+}
+
+fn key_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("key")
+            .short("k")
+            .long("key")
+            .help("set the key for encryption communication, visible to other users")
+            .takes_value(true),
+        Arg::with_name("key-file")
+            .long("key-file")
+            .help("read the key from a file")
+            .takes_value(true),
+        Arg::with_name("key-prompt")
+            .long("key-prompt")
+            .help("prompt for the key"),
+    ]
+}
+
+/// Looks for the key on the command line, then in the environment, then in
+/// the config file, and finally prompts for it on a terminal.
+fn get_key(
+    matches: &ArgMatches,
+    key: Option<Zeroizing<String>>,
+    key_file: Option<String>,
+) -> Result<Zeroizing<String>, String> {
+    if let Some(key) = matches.value_of("key") {
+        return Ok(Zeroizing::new(String::from(key)));
+    }
+    if let Some(path) = matches.value_of("key-file") {
+        return config::read_key_file(path);
+    }
+    if matches.is_present("key-prompt") {
+        return utils::prompt_password("Key: ");
+    }
+    if let Some(key) = env::var_os(KEY_ENV) {
+        // Keep the key out of the environment of the commands we spawn.
+        env::remove_var(KEY_ENV);
+        let key = key
+            .into_string()
+            .map_err(|_| format!("{} is not valid UTF-8", KEY_ENV))?;
+        return Ok(Zeroizing::new(key));
+    }
+    if let Some(key) = key {
+        return Ok(key);
+    }
+    if let Some(path) = key_file {
+        return config::read_key_file(&path);
+    }
+    if utils::stdin_is_tty() {
+        return utils::prompt_password("Key: ");
+    }
+    Err(format!(
+        "missing key: set --key-file, {} or `key-file` in the config file",
+        KEY_ENV
+    ))
+}
+
+fn config_arg() -> Arg<'static, 'static> {
+    Arg::with_name("config")
+        .short("c")
+        .long("config")
+        .help("read options from a TOML file, command line flags take precedence")
+        .takes_value(true)
+}
+
+fn control_arg() -> Arg<'static, 'static> {
+    Arg::with_name("control")
+        .long("control")
+        .help("set the path of the server's control socket, default /run/kytan.sock")
+        .takes_value(true)
+}
+
+fn metrics_arg() -> Arg<'static, 'static> {
+    Arg::with_name("metrics")
+        .long("metrics")
+        .help("serve Prometheus metrics over HTTP on this address, e.g. 127.0.0.1:9528")
+        .takes_value(true)
+}
+
+fn interface_arg() -> Arg<'static, 'static> {
+    Arg::with_name("interface")
+        .long("interface")
+        .help("set the name of the TUN device, where %d picks a free number, e.g. kytan%d")
+        .takes_value(true)
+}
+
+fn mtu_arg() -> Arg<'static, 'static> {
+    Arg::with_name("mtu")
+        .long("mtu")
+        .help("set the MTU of the TUN device, default 1380")
+        .takes_value(true)
+}
+
+/// The MTU from the command line or config file, checked against the range
+/// kytan supports.
+fn get_mtu(matches: &ArgMatches, file: Option<u32>) -> Result<u32, String> {
+    let mtu = value(matches, "mtu")?.or(file).unwrap_or(device::DEFAULT_MTU);
+    if !(device::MIN_MTU..=device::MAX_MTU).contains(&mtu) {
+        return Err(format!(
+            "--mtu: {} is not between {} and {}",
+            mtu,
+            device::MIN_MTU,
+            device::MAX_MTU
+        ));
+    }
+    Ok(mtu)
+}
+
+fn tap_arg() -> Arg<'static, 'static> {
+    Arg::with_name("tap")
+        .long("tap")
+        .help("carry Ethernet frames through a TAP device instead of IP packets (Linux)")
+}
+
+fn target_arg() -> Arg<'static, 'static> {
+    Arg::with_name("target")
+        .help("session id, tunnel address, endpoint or peer name")
+        .required(true)
+        .index(1)
+}
+
+fn get_sandbox(
+    matches: &ArgMatches,
+    user: Option<String>,
+    group: Option<String>,
+    chroot: Option<String>,
+    seccomp: Option<bool>,
+) -> privilege::Sandbox {
+    privilege::Sandbox {
+        user: matches.value_of("user").map(String::from).or(user),
+        group: matches.value_of("group").map(String::from).or(group),
+        chroot: matches.value_of("chroot").map(String::from).or(chroot),
+        seccomp: matches.is_present("seccomp") || seccomp.unwrap_or(false),
+    }
+}
+
+fn value<T>(matches: &ArgMatches, name: &str) -> Result<Option<T>, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match matches.value_of(name) {
+        Some(v) => v
+            .parse::<T>()
+            .map(Some)
+            .map_err(|e| format!("--{}: {}", name, e)),
+        None => Ok(None),
+    }
+}
+
+fn values<T>(matches: &ArgMatches, name: &str) -> Result<Option<Vec<T>>, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match matches.values_of(name) {
+        Some(vs) => vs
+            .map(|v| v.parse::<T>().map_err(|e| format!("--{}: {}", name, e)))
+            .collect::<Result<Vec<T>, String>>()
+            .map(Some),
+        None => Ok(None),
+    }
+}
+
+fn required<T>(value: Option<T>, name: &str) -> Result<T, String> {
+    value.ok_or_else(|| format!("missing {0}: set --{0} or `{0}` in the config file", name))
+}
+
+fn app() -> App<'static, 'static> {
+    App::new("kytan: High Performance Peer-to-Peer VPN")
         .version("1.0")
         .subcommand(
             SubCommand::with_name("server")
                 .help("client mode")
                 .arg(
-                    Arg::with_name("bind")
+                    Arg::with_name("listen")
                         .short("l")
                         .long("listen")
-                        .default_value("0.0.0.0")
-                        .help("set the listen address")
+                        .help("set the listen address, default 0.0.0.0")
                         .takes_value(true),
                 )
                 .arg(
                     Arg::with_name("port")
                         .short("p")
                         .long("port")
-                        .default_value("9527")
-                        .help("set the listen port")
-                        .takes_value(true),
-                )
-                .arg(
-                    Arg::with_name("key")
-                        .short("k")
-                        .long("key")
-                        .help("set the key for encryption communication")
+                        .help("set the listen port, default 9527")
                         .takes_value(true),
                 )
+                .args(&key_args())
                 .arg(
                     Arg::with_name("dns")
                         .short("d")
                         .long("dns")
-                        .default_value("8.8.8.8")
-                        .help("set dns for client, default 8.8.8.8")
+                        .help("set dns servers for client, default 8.8.8.8")
+                        .multiple(true)
+                        .use_delimiter(true)
                         .takes_value(true),
-                ),
+                )
+                .arg(
+                    Arg::with_name("public-address")
+                        .long("public-address")
+                        .help("set the address clients reach the server at")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("dns-search")
+                        .long("dns-search")
+                        .help("set dns search domains for client")
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("dns-split")
+                        .long("dns-split")
+                        .help("only resolve these domains through the tunnel")
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("push-route")
+                        .long("push-route")
+                        .help("route this network through the tunnel on every client")
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("push-default-route")
+                        .long("push-default-route")
+                        .possible_values(&["true", "false"])
+                        .help("set whether clients route all traffic through the tunnel")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("client-to-client")
+                        .long("client-to-client")
+                        .possible_values(&["kernel", "allow", "deny", "acl"])
+                        .help("set how packets between clients are forwarded, default kernel")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("client-acl")
+                        .long("client-acl")
+                        .help("let two networks talk with --client-to-client acl, e.g. A:B")
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("quota-daily")
+                        .long("quota-daily")
+                        .help("limit the traffic of each client per day, e.g. 10G")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("quota-monthly")
+                        .long("quota-monthly")
+                        .help("limit the traffic of each client per month, e.g. 100G")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("quota-action")
+                        .long("quota-action")
+                        .possible_values(&["disconnect", "throttle"])
+                        .help("set what happens to clients over quota, default disconnect")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("quota-throttle")
+                        .long("quota-throttle")
+                        .help("set the bytes per second left to throttled clients, default 128K")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("quota-state")
+                        .long("quota-state")
+                        .help("set the file traffic is saved to, default /var/lib/kytan/usage.json")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("rate-up")
+                        .long("rate-up")
+                        .help("limit the bytes per second each client may send, e.g. 1M")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("rate-down")
+                        .long("rate-down")
+                        .help("limit the bytes per second each client may receive, e.g. 10M")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("on-connect")
+                        .long("on-connect")
+                        .help("run this shell command when a client connects")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("on-disconnect")
+                        .long("on-disconnect")
+                        .help("run this shell command when a client disconnects")
+                        .takes_value(true),
+                )
+                .arg(tap_arg())
+                .arg(interface_arg())
+                .arg(mtu_arg())
+                .arg(config_arg())
+                .arg(control_arg())
+                .arg(metrics_arg())
+                .args(&sandbox_args()),
         )
         .subcommand(
             SubCommand::with_name("client")
@@ -78,14 +443,14 @@ pub fn get_args() -> Result<Args, String> {
                     Arg::with_name("port")
                         .short("p")
                         .long("port")
-                        .help("set the remote port")
+                        .help("set the remote port, default 9527")
                         .takes_value(true),
                 )
+                .args(&key_args())
                 .arg(
-                    Arg::with_name("key")
-                        .short("k")
-                        .long("key")
-                        .help("set the key for encryption communication")
+                    Arg::with_name("name")
+                        .long("name")
+                        .help("set the name announced to the server")
                         .takes_value(true),
                 )
                 .arg(
@@ -93,60 +458,346 @@ pub fn get_args() -> Result<Args, String> {
                         .short("n")
                         .long("no-default-route")
                         .help("do not set default route"),
+                )
+                .arg(
+                    Arg::with_name("dns-backend")
+                        .long("dns-backend")
+                        .possible_values(&["auto", "resolved", "resolvconf", "file"])
+                        .help("set how the pushed dns is applied, default auto")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("route")
+                        .long("route")
+                        .help("route this network through the tunnel, e.g. 192.168.0.0/16")
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("subnet")
+                        .long("subnet")
+                        .help("announce a network behind this client, e.g. 192.168.5.0/24")
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("exclude")
+                        .long("exclude")
+                        .help("never route this network through the tunnel, e.g. 192.168.1.0/24")
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("fwmark")
+                        .long("fwmark")
+                        .help("use policy routing with this mark and table, e.g. 51820 (Linux)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("kill-switch")
+                        .long("kill-switch")
+                        .help("block traffic outside the tunnel while connected (Linux)"),
+                )
+                .arg(
+                    Arg::with_name("up")
+                        .long("up")
+                        .help("run this shell command once the tunnel is up")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("down")
+                        .long("down")
+                        .help("run this shell command before the tunnel is torn down")
+                        .takes_value(true),
+                )
+                .arg(tap_arg())
+                .arg(interface_arg())
+                .arg(mtu_arg())
+                .arg(
+                    Arg::with_name("no-pmtu-discovery")
+                        .long("no-pmtu-discovery")
+                        .help("do not probe the path to the server for its MTU"),
+                )
+                .arg(config_arg())
+                .arg(metrics_arg())
+                .args(&sandbox_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("status")
+                .about("show the sessions of a running server")
+                .arg(control_arg())
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("print JSON instead of a table"),
                 ),
         )
-        .get_matches();
+        .subcommand(
+            SubCommand::with_name("usage")
+                .about("show the traffic of every client of a running server")
+                .arg(control_arg())
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("print JSON instead of a table"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("kick")
+                .about("close a session of a running server")
+                .arg(target_arg())
+                .arg(
+                    Arg::with_name("ban")
+                        .long("ban")
+                        .help("refuse reconnections until unbanned"),
+                )
+                .arg(control_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("ban")
+                .about("close a session and refuse reconnections, same as kick --ban")
+                .arg(target_arg())
+                .arg(control_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("unban")
+                .about("accept a banned peer name or endpoint address again")
+                .arg(target_arg())
+                .arg(control_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("unblock")
+                .about("remove the kill switch left behind by a client that did not exit cleanly"),
+        )
+        .subcommand(
+            SubCommand::with_name("cleanup")
+                .about("undo the changes left behind by a client that did not exit cleanly")
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("undo them even if the client is still running"),
+                ),
+        )
+}
+
+fn get_control(matches: &ArgMatches) -> Result<String, String> {
+    Ok(value(matches, "control")?.unwrap_or_else(|| String::from(control::DEFAULT_SOCKET)))
+}
+
+pub fn get_args() -> Result<Args, String> {
+    let matches = app().get_matches();
     if let Some(matches) = matches.subcommand_matches("client") {
-        let ip_str = matches
-            .value_of("server")
-            .ok_or_else(|| "can not find client host value")
-            .unwrap();
-        let port_str = matches
-            .value_of("port")
-            .ok_or_else(|| "can not find client port value")
-            .unwrap();
-        let key_str = matches
-            .value_of("key")
-            .ok_or_else(|| "can not find client key value")
-            .unwrap();
-        // let remote_addr = IpAddr::V4(Ipv4Addr::from_str(ip_str).map_err(|e| e.to_string())?);
-        let port = port_str.parse::<u16>().map_err(|e| e.to_string())?;
-        let default_route = match matches.is_present("no-default-route") {
-            false => true,
-            true => false,
+        let file = match matches.value_of("config") {
+            Some(path) => config::load_client(path)?,
+            None => config::ClientConfig::default(),
         };
+        let remote_addr = required(value::<String>(matches, "server")?.or(file.server), "server")?;
+        let key = get_key(matches, file.key, file.key_file)?;
+        let port = value(matches, "port")?.or(file.port).unwrap_or(9527);
+        let name = value(matches, "name")?.or(file.name).unwrap_or_default();
+        let default_route =
+            !(matches.is_present("no-default-route") || file.no_default_route.unwrap_or(false));
+        let routes = values(matches, "route")?.or(file.route).unwrap_or_default();
+        let excludes = values(matches, "exclude")?
+            .or(file.exclude)
+            .unwrap_or_default();
+        let subnets = values(matches, "subnet")?
+            .or(file.subnet)
+            .unwrap_or_default();
+        let dns_backend = match value::<String>(matches, "dns-backend")?.or(file.dns_backend) {
+            Some(backend) => backend.parse::<dns::Backend>()?,
+            None => dns::Backend::Auto,
+        };
+        let fwmark = value(matches, "fwmark")?.or(file.fwmark);
+        if fwmark.is_some() && !cfg!(target_os = "linux") {
+            return Err(String::from("--fwmark is only supported on Linux"));
+        }
+        let tap = matches.is_present("tap") || file.tap.unwrap_or(false);
+        if tap && !cfg!(target_os = "linux") {
+            return Err(String::from("--tap is only supported on Linux"));
+        }
+        // Probes rely on IP_MTU_DISCOVER, so there is no discovery elsewhere.
+        let pmtu_discovery = cfg!(target_os = "linux")
+            && !(matches.is_present("no-pmtu-discovery")
+                || file.no_pmtu_discovery.unwrap_or(false));
         Ok(Args::Client(Client {
-            remote_addr: ip_str.to_string(),
+            remote_addr,
             port: port,
-            key: key_str.to_string(),
+            key,
+            name,
             default_route: default_route,
+            routes,
+            excludes,
+            subnets,
+            fwmark,
+            kill_switch: matches.is_present("kill-switch") || file.kill_switch.unwrap_or(false),
+            tap,
+            interface: value(matches, "interface")?.or(file.interface),
+            mtu: get_mtu(matches, file.mtu)?,
+            pmtu_discovery,
+            dns_backend,
+            sandbox: get_sandbox(matches, file.user, file.group, file.chroot, file.seccomp),
+            metrics: value(matches, "metrics")?.or(file.metrics),
+            up: value(matches, "up")?.or(file.up),
+            down: value(matches, "down")?.or(file.down),
         }))
     } else if let Some(matches) = matches.subcommand_matches("server") {
-        let ip_str = matches
-            .value_of("bind")
-            .ok_or_else(|| "can not find server host value")
-            .unwrap();
-        let port_str = matches
-            .value_of("port")
-            .ok_or_else(|| "can not find server port value")
-            .unwrap();
-        let key_str = matches
-            .value_of("key")
-            .ok_or_else(|| "can not find server key value")
-            .unwrap();
-        let dns = matches
-            .value_of("dns")
-            .ok_or_else(|| "can not find dns value")?;
-        // let bind_addr = IpAddr::V4(Ipv4Addr::from_str(ip_str).map_err(|e| e.to_string())?);
-        let dns = IpAddr::V4(Ipv4Addr::from_str(dns).map_err(|e| e.to_string())?);
-        let port = port_str.parse::<u16>().map_err(|e| e.to_string())?;
-        Ok(Args::Server(Server {
-            bind_addr: ip_str.to_string(),
-            port: port,
-            key: key_str.to_string(),
-            dns: dns,
+        let mut file = match matches.value_of("config") {
+            Some(path) => config::load_server(path)?,
+            None => config::ServerConfig::default(),
+        };
+        let key = get_key(matches, file.key.take(), file.key_file.take())?;
+        Ok(Args::Server(get_server(matches, file, key)?))
+    } else if let Some(matches) = matches.subcommand_matches("status") {
+        Ok(Args::Status(Status {
+            control: get_control(matches)?,
+            json: matches.is_present("json"),
+        }))
+    } else if let Some(matches) = matches.subcommand_matches("usage") {
+        Ok(Args::Usage(Status {
+            control: get_control(matches)?,
+            json: matches.is_present("json"),
+        }))
+    } else if let Some(kick) = matches
+        .subcommand_matches("kick")
+        .or_else(|| matches.subcommand_matches("ban"))
+    {
+        Ok(Args::Command(Command {
+            control: get_control(kick)?,
+            request: control::Request::Kick {
+                target: String::from(kick.value_of("target").unwrap()),
+                ban: matches.subcommand_name() == Some("ban") || kick.is_present("ban"),
+            },
         }))
+    } else if let Some(matches) = matches.subcommand_matches("unban") {
+        Ok(Args::Command(Command {
+            control: get_control(matches)?,
+            request: control::Request::Unban {
+                target: String::from(matches.value_of("target").unwrap()),
+            },
+        }))
+    } else if matches.subcommand_matches("unblock").is_some() {
+        Ok(Args::Unblock)
+    } else if let Some(matches) = matches.subcommand_matches("cleanup") {
+        Ok(Args::Cleanup {
+            force: matches.is_present("force"),
+        })
     } else {
         unimplemented!()
     }
 }
+
+fn get_server(
+    matches: &ArgMatches,
+    file: config::ServerConfig,
+    key: Zeroizing<String>,
+) -> Result<Server, String> {
+    let bind_addr = value(matches, "listen")?
+        .or(file.listen)
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    let port = value(matches, "port")?.or(file.port).unwrap_or(9527);
+    let dns = dns::Settings {
+        servers: values(matches, "dns")?
+            .or(file.dns)
+            .unwrap_or_else(|| vec!["8.8.8.8".parse().unwrap()]),
+        search: values(matches, "dns-search")?
+            .or(file.dns_search)
+            .unwrap_or_default(),
+        split: values(matches, "dns-split")?
+            .or(file.dns_split)
+            .unwrap_or_default(),
+    };
+    let routing = utils::Routing {
+        routes: values(matches, "push-route")?
+            .or(file.push_route)
+            .unwrap_or_default(),
+        default_route: value(matches, "push-default-route")?.or(file.push_default_route),
+    };
+    let client_to_client = acl::Policy::new(
+        &value::<String>(matches, "client-to-client")?
+            .or(file.client_to_client)
+            .unwrap_or_else(|| String::from("kernel")),
+        values(matches, "client-acl")?
+            .or(file.client_acl)
+            .unwrap_or_default(),
+    )?;
+    let tap = matches.is_present("tap") || file.tap.unwrap_or(false);
+    if tap && matches!(client_to_client, acl::Policy::Acl(_)) {
+        return Err(String::from("--client-to-client acl is not supported with --tap"));
+    }
+    let public_addr = value(matches, "public-address")?.or(file.public_address);
+    let quota_action = match value::<String>(matches, "quota-action")?
+        .or(file.quota_action)
+        .as_deref()
+    {
+        None | Some("disconnect") => quota::Action::Disconnect,
+        Some("throttle") => quota::Action::Throttle(
+            value::<quota::Bytes>(matches, "quota-throttle")?
+                .or(file.quota_throttle)
+                .map_or(quota::DEFAULT_THROTTLE, |rate| rate.0),
+        ),
+        Some(action) => return Err(format!("unknown quota action: {}", action)),
+    };
+    let quota = quota::Quota {
+        daily: value::<quota::Bytes>(matches, "quota-daily")?
+            .or(file.quota_daily)
+            .map(|bytes| bytes.0),
+        monthly: value::<quota::Bytes>(matches, "quota-monthly")?
+            .or(file.quota_monthly)
+            .map(|bytes| bytes.0),
+        action: quota_action,
+    };
+    let sandbox = get_sandbox(matches, file.user, file.group, file.chroot, file.seccomp);
+    if sandbox.chroot.is_some() && file.peers.iter().any(|peer| peer.subnets.is_some()) {
+        return Err(String::from("subnets of peers cannot be routed with --chroot"));
+    }
+    Ok(Server {
+        bind_addr,
+        port,
+        key,
+        dns,
+        routing,
+        public_addr,
+        sandbox,
+        peers: file.peers,
+        control: value(matches, "control")?
+            .or(file.control)
+            .unwrap_or_else(|| String::from(control::DEFAULT_SOCKET)),
+        metrics: value(matches, "metrics")?.or(file.metrics),
+        quota,
+        quota_state: value(matches, "quota-state")?
+            .or(file.quota_state)
+            .unwrap_or_else(|| String::from(quota::DEFAULT_STATE)),
+        rate: ratelimit::Limits {
+            up: value::<quota::Bytes>(matches, "rate-up")?
+                .or(file.rate_up)
+                .map(|bytes| bytes.0),
+            down: value::<quota::Bytes>(matches, "rate-down")?
+                .or(file.rate_down)
+                .map(|bytes| bytes.0),
+        },
+        on_connect: value(matches, "on-connect")?.or(file.on_connect),
+        on_disconnect: value(matches, "on-disconnect")?.or(file.on_disconnect),
+        client_to_client,
+        tap,
+        interface: value(matches, "interface")?.or(file.interface),
+        mtu: get_mtu(matches, file.mtu)?,
+    })
+}
+
+/// Parses the server's command line and config file again, e.g. on SIGHUP.
+/// The key is left empty since it cannot be changed at runtime.
+pub fn reload_server() -> Result<Server, String> {
+    let matches = app().get_matches_safe().map_err(|e| e.message)?;
+    let matches = matches
+        .subcommand_matches("server")
+        .ok_or("not running in server mode")?;
+    let file = match matches.value_of("config") {
+        Some(path) => config::load_server(path)?,
+        None => config::ServerConfig::default(),
+    };
+    get_server(matches, file, Zeroizing::new(String::new()))
+}
@@ -0,0 +1,277 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::acl::Rule;
+use crate::quota::Bytes;
+use crate::utils::Ipv4Net;
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde_derive::Deserialize;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::unix::fs::PermissionsExt;
+use zeroize::Zeroizing;
+
+/// Keys mirror the long command line flags of `kytan server`.
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ServerConfig {
+    pub listen: Option<IpAddr>,
+    pub port: Option<u16>,
+    pub key: Option<Zeroizing<String>>,
+    pub key_file: Option<String>,
+    pub public_address: Option<IpAddr>,
+    pub dns: Option<Vec<IpAddr>>,
+    pub dns_search: Option<Vec<String>>,
+    pub dns_split: Option<Vec<String>>,
+    pub push_route: Option<Vec<Ipv4Net>>,
+    pub push_default_route: Option<bool>,
+    pub client_to_client: Option<String>,
+    pub client_acl: Option<Vec<Rule>>,
+    pub tap: Option<bool>,
+    pub interface: Option<String>,
+    pub mtu: Option<u32>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub chroot: Option<String>,
+    pub seccomp: Option<bool>,
+    pub control: Option<String>,
+    pub metrics: Option<SocketAddr>,
+    pub quota_daily: Option<Bytes>,
+    pub quota_monthly: Option<Bytes>,
+    pub quota_action: Option<String>,
+    pub quota_throttle: Option<Bytes>,
+    pub quota_state: Option<String>,
+    pub rate_up: Option<Bytes>,
+    pub rate_down: Option<Bytes>,
+    pub on_connect: Option<String>,
+    pub on_disconnect: Option<String>,
+    #[serde(default, rename = "peer")]
+    pub peers: Vec<Peer>,
+}
+
+/// Keys mirror the long command line flags of `kytan client`.
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ClientConfig {
+    pub server: Option<String>,
+    pub port: Option<u16>,
+    pub key: Option<Zeroizing<String>>,
+    pub key_file: Option<String>,
+    pub name: Option<String>,
+    pub no_default_route: Option<bool>,
+    pub dns_backend: Option<String>,
+    pub route: Option<Vec<Ipv4Net>>,
+    pub exclude: Option<Vec<Ipv4Net>>,
+    pub subnet: Option<Vec<Ipv4Net>>,
+    pub fwmark: Option<u32>,
+    pub kill_switch: Option<bool>,
+    pub tap: Option<bool>,
+    pub interface: Option<String>,
+    pub mtu: Option<u32>,
+    pub no_pmtu_discovery: Option<bool>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub chroot: Option<String>,
+    pub seccomp: Option<bool>,
+    pub metrics: Option<SocketAddr>,
+    pub up: Option<String>,
+    pub down: Option<String>,
+}
+
+/// A client the server accepts, identified by the name it announces.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Peer {
+    pub name: String,
+    /// Static tunnel address, e.g. 10.10.10.100.
+    pub address: Option<Ipv4Addr>,
+    /// Override the server's quotas and rate limits for this peer.
+    pub quota_daily: Option<Bytes>,
+    pub quota_monthly: Option<Bytes>,
+    pub rate_up: Option<Bytes>,
+    pub rate_down: Option<Bytes>,
+    /// Networks behind this peer that it may announce.
+    pub subnets: Option<Vec<Ipv4Net>>,
+}
+
+fn warn_if_world_readable(path: &str) {
+    if let Ok(metadata) = fs::metadata(path) {
+        if metadata.permissions().mode() & 0o004 != 0 {
+            warn!("{} contains a key and is readable by every user.", path);
+        }
+    }
+}
+
+fn load<T: DeserializeOwned>(path: &str) -> Result<T, String> {
+    // The file may hold the key, so wipe it once parsed.
+    let content = Zeroizing::new(fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?);
+    if content.contains("key") {
+        warn_if_world_readable(path);
+    }
+    toml::from_str(&content).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Reads a key from the first line of `path`.
+pub fn read_key_file(path: &str) -> Result<Zeroizing<String>, String> {
+    warn_if_world_readable(path);
+    let mut key = Zeroizing::new(fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?);
+    let len = key.lines().next().unwrap_or("").len();
+    key.truncate(len);
+    if key.is_empty() {
+        return Err(format!("{}: the key is empty", path));
+    }
+    Ok(key)
+}
+
+pub fn load_server(path: &str) -> Result<ServerConfig, String> {
+    let config: ServerConfig = load(path)?;
+    validate_peers(&config.peers).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(config)
+}
+
+pub fn load_client(path: &str) -> Result<ClientConfig, String> {
+    load(path)
+}
+
+fn validate_peers(peers: &[Peer]) -> Result<(), String> {
+    for (i, peer) in peers.iter().enumerate() {
+        if peer.name.is_empty() {
+            return Err(format!("peer #{} has an empty name", i + 1));
+        }
+        if peers[..i].iter().any(|p| p.name == peer.name) {
+            return Err(format!("peer {} is listed more than once", peer.name));
+        }
+        if let Some(address) = peer.address {
+            let octets = address.octets();
+            if octets[..3] != [10, 10, 10] || octets[3] < 2 || octets[3] > 253 {
+                return Err(format!(
+                    "peer {} has address {} outside of 10.10.10.2-10.10.10.253",
+                    peer.name, address
+                ));
+            }
+            if peers[..i].iter().any(|p| p.address == Some(address)) {
+                return Err(format!("address {} is assigned more than once", address));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::*;
+
+    #[test]
+    fn server_config_test() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            port = 9527
+            key = "hello"
+            dns = ["8.8.8.8", "2001:4860:4860::8888"]
+            dns-split = ["corp.example"]
+            quota-daily = "10G"
+            rate-down = "10M"
+            push-route = ["192.168.10.0/24"]
+            push-default-route = false
+            client-to-client = "acl"
+            client-acl = ["10.10.10.100:192.168.5.0/24"]
+
+            [[peer]]
+            name = "laptop"
+            address = "10.10.10.100"
+            subnets = ["192.168.5.0/24"]
+
+            [[peer]]
+            name = "phone"
+            quota-monthly = "500M"
+            rate-up = "1M"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.port, Some(9527));
+        assert_eq!(config.key.as_deref().map(String::as_str), Some("hello"));
+        assert_eq!(config.dns.unwrap().len(), 2);
+        assert_eq!(config.peers.len(), 2);
+        assert_eq!(config.peers[1].address, None);
+        assert_eq!(config.peers[0].subnets.as_ref().unwrap().len(), 1);
+        assert_eq!(config.quota_daily, Some(Bytes(10 << 30)));
+        assert_eq!(config.peers[1].quota_monthly, Some(Bytes(500 << 20)));
+        assert_eq!(config.rate_down, Some(Bytes(10 << 20)));
+        assert_eq!(config.peers[1].rate_up, Some(Bytes(1 << 20)));
+        assert_eq!(config.push_route.unwrap()[0].to_string(), "192.168.10.0/24");
+        assert_eq!(config.push_default_route, Some(false));
+        assert_eq!(config.client_acl.unwrap().len(), 1);
+        validate_peers(&config.peers).unwrap();
+    }
+
+    #[test]
+    fn client_config_test() {
+        let config: ClientConfig = toml::from_str(
+            r#"
+            server = "vpn.example.com"
+            no-default-route = true
+            route = ["192.168.0.0/16"]
+            fwmark = 51820
+            tap = true
+            interface = "kytan%d"
+            mtu = 1280
+            no-pmtu-discovery = true
+            metrics = "127.0.0.1:9528"
+            up = "logger kytan up $KYTAN_INTERFACE"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.server, Some(String::from("vpn.example.com")));
+        assert_eq!(config.no_default_route, Some(true));
+        assert_eq!(config.route.unwrap()[0].to_string(), "192.168.0.0/16");
+        assert_eq!(config.port, None);
+        assert_eq!(config.fwmark, Some(51820));
+        assert_eq!(config.tap, Some(true));
+        assert_eq!(config.interface.unwrap(), "kytan%d");
+        assert_eq!(config.mtu, Some(1280));
+        assert_eq!(config.no_pmtu_discovery, Some(true));
+        assert_eq!(config.metrics, Some("127.0.0.1:9528".parse().unwrap()));
+        assert_eq!(config.up.unwrap(), "logger kytan up $KYTAN_INTERFACE");
+    }
+
+    #[test]
+    fn read_key_file_test() {
+        let path = std::env::temp_dir().join(format!("kytan-key-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, "hello\n").unwrap();
+        assert_eq!(read_key_file(path).unwrap().as_str(), "hello");
+        fs::write(path, "\n").unwrap();
+        assert!(read_key_file(path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn invalid_config_test() {
+        assert!(toml::from_str::<ClientConfig>("sever = \"typo\"").is_err());
+        assert!(toml::from_str::<ServerConfig>("port = \"9527\"").is_err());
+        assert!(toml::from_str::<ServerConfig>("listen = \"0.0.0.256\"").is_err());
+        assert!(toml::from_str::<ServerConfig>("quota-daily = \"10X\"").is_err());
+        assert!(toml::from_str::<ClientConfig>("route = [\"10.0.0.1/8\"]").is_err());
+        let peer = |name: &str, address: &str| Peer {
+            name: String::from(name),
+            address: Some(address.parse().unwrap()),
+            ..Default::default()
+        };
+        assert!(validate_peers(&[peer("a", "10.10.10.2"), peer("a", "10.10.10.3")]).is_err());
+        assert!(validate_peers(&[peer("a", "10.10.10.2"), peer("b", "10.10.10.2")]).is_err());
+        assert!(validate_peers(&[peer("a", "10.10.10.1")]).is_err());
+        assert!(validate_peers(&[peer("a", "192.168.0.2")]).is_err());
+    }
+}
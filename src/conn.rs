@@ -0,0 +1,204 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use log::warn;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+
+/// Connections served at once by a listener, each with a token of its own.
+pub const MAX_CONNECTIONS: usize = 16;
+/// How long a connection may take to send its request and read the response.
+const TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_LEN: usize = 4096;
+
+struct Conn<S> {
+    stream: S,
+    request: Vec<u8>,
+    response: Option<Vec<u8>>,
+    written: usize,
+    opened: Instant,
+}
+
+/// Connections that send one line and get one response, read and written
+/// without blocking so that a slow or idle peer cannot stall the event loop.
+pub struct Connections<S> {
+    first: usize,
+    conns: HashMap<usize, Conn<S>>,
+}
+
+impl<S: Read + Write + AsRawFd> Connections<S> {
+    /// The connections get the tokens from `first` on.
+    pub fn new(first: mio::Token) -> Connections<S> {
+        Connections {
+            first: first.0,
+            conns: HashMap::new(),
+        }
+    }
+
+    pub fn owns(&self, token: mio::Token) -> bool {
+        (self.first..self.first + MAX_CONNECTIONS).contains(&token.0)
+    }
+
+    /// Registers an accepted stream, which has to be non-blocking. Makes room
+    /// by closing the oldest connection if every token is taken.
+    pub fn add(&mut self, registry: &mio::Registry, stream: S) -> Result<(), String> {
+        let now = Instant::now();
+        let expired: Vec<usize> = self
+            .conns
+            .iter()
+            .filter(|&(_, conn)| now.duration_since(conn.opened) > TIMEOUT)
+            .map(|(&token, _)| token)
+            .collect();
+        for token in expired {
+            self.close(registry, token);
+        }
+        if self.conns.len() == MAX_CONNECTIONS {
+            let oldest = self.conns.iter().min_by_key(|&(_, conn)| conn.opened);
+            let oldest = *oldest.unwrap().0;
+            warn!("Too many connections. Closing the oldest.");
+            self.close(registry, oldest);
+        }
+        let token = (self.first..self.first + MAX_CONNECTIONS)
+            .find(|token| !self.conns.contains_key(token))
+            .unwrap();
+        registry
+            .register(
+                &mut mio::unix::SourceFd(&stream.as_raw_fd()),
+                mio::Token(token),
+                mio::Interest::READABLE | mio::Interest::WRITABLE,
+            )
+            .map_err(|e| e.to_string())?;
+        self.conns.insert(
+            token,
+            Conn {
+                stream,
+                request: Vec::new(),
+                response: None,
+                written: 0,
+                opened: now,
+            },
+        );
+        Ok(())
+    }
+
+    /// Reads and writes what the connection of `token` allows. Once its
+    /// request line is complete, `handler` turns it into the response.
+    pub fn ready<F>(&mut self, registry: &mio::Registry, token: mio::Token, handler: F)
+    where
+        F: FnOnce(&str) -> Vec<u8>,
+    {
+        let done = match self.conns.get_mut(&token.0) {
+            Some(conn) => match conn.progress(handler) {
+                Ok(done) => done,
+                Err(e) => {
+                    warn!("{}", e);
+                    true
+                }
+            },
+            None => false,
+        };
+        if done {
+            self.close(registry, token.0);
+        }
+    }
+
+    fn close(&mut self, registry: &mio::Registry, token: usize) {
+        if let Some(conn) = self.conns.remove(&token) {
+            let _ = registry.deregister(&mut mio::unix::SourceFd(&conn.stream.as_raw_fd()));
+        }
+    }
+}
+
+impl<S: Read + Write> Conn<S> {
+    /// Returns whether the connection is done with.
+    fn progress<F>(&mut self, handler: F) -> Result<bool, String>
+    where
+        F: FnOnce(&str) -> Vec<u8>,
+    {
+        if self.response.is_none() {
+            let mut buf = [0u8; 1024];
+            loop {
+                match self.stream.read(&mut buf) {
+                    Ok(0) => return Ok(true),
+                    Ok(len) => self.request.extend_from_slice(&buf[..len]),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e.to_string()),
+                }
+                if self.request.contains(&b'\n') {
+                    break;
+                }
+                if self.request.len() > MAX_REQUEST_LEN {
+                    return Err(String::from("request too long"));
+                }
+            }
+            match self.request.iter().position(|&b| b == b'\n') {
+                Some(end) => {
+                    let line = String::from_utf8_lossy(&self.request[..end]).into_owned();
+                    self.response = Some(handler(&line));
+                }
+                None => return Ok(false),
+            }
+        }
+        let response = self.response.as_ref().unwrap();
+        while self.written < response.len() {
+            match self.stream.write(&response[self.written..]) {
+                Ok(len) => self.written += len,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conn::*;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn connections_test() {
+        let poll = mio::Poll::new().unwrap();
+        let mut conns = Connections::new(mio::Token(16));
+        assert!(conns.owns(mio::Token(16)));
+        assert!(!conns.owns(mio::Token(16 + MAX_CONNECTIONS)));
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        server.set_nonblocking(true).unwrap();
+        conns.add(poll.registry(), server).unwrap();
+        // Nothing is answered before the line is complete.
+        client.write_all(b"ping").unwrap();
+        conns.ready(poll.registry(), mio::Token(16), |_| unreachable!());
+        client.write_all(b"\nignored").unwrap();
+        conns.ready(poll.registry(), mio::Token(16), |line| {
+            assert_eq!(line, "ping");
+            b"pong\n".to_vec()
+        });
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "pong\n");
+        assert!(conns.conns.is_empty());
+
+        // Idle connections make room for new ones.
+        for _ in 0..MAX_CONNECTIONS + 1 {
+            let (_, server) = UnixStream::pair().unwrap();
+            conns.add(poll.registry(), server).unwrap();
+        }
+        assert_eq!(conns.conns.len(), MAX_CONNECTIONS);
+    }
+}
@@ -0,0 +1,381 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::conn;
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_SOCKET: &str = "/run/kytan.sock";
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One JSON object per line, sent by `kytan` subcommands to a running server.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    Status,
+    Usage,
+    /// Closes the sessions matching `target`, an id, address, endpoint or name.
+    Kick {
+        target: String,
+        #[serde(default)]
+        ban: bool,
+    },
+    Unban {
+        target: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Response {
+    Status(Vec<SessionInfo>),
+    Usage(Vec<UsageInfo>),
+    Done(String),
+    Error(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SessionInfo {
+    pub id: u8,
+    pub address: String,
+    pub endpoint: String,
+    pub name: String,
+    pub mtu: u32,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Packets dropped by rate limits.
+    pub dropped_in: u64,
+    pub dropped_out: u64,
+    /// Seconds since the Unix epoch.
+    pub last_handshake: u64,
+    pub last_packet: Option<u64>,
+}
+
+/// Traffic of a client identity, kept across its sessions.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UsageInfo {
+    pub identity: String,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub today: u64,
+    pub this_month: u64,
+}
+
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// The server end of the control socket. The socket file is removed when dropped.
+pub struct Listener {
+    listener: UnixListener,
+    path: PathBuf,
+    conns: conn::Connections<UnixStream>,
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Listener {
+    /// Connections get the tokens from `first` on.
+    pub fn bind(path: &str, first: mio::Token) -> Result<Listener, String> {
+        // A socket left behind by a previous run refuses new binds.
+        if UnixStream::connect(path).is_ok() {
+            return Err(format!("{}: another instance is running", path));
+        }
+        let _ = fs::remove_file(path);
+        // Only the owner may talk to the control socket.
+        let umask = unsafe { libc::umask(0o077) };
+        let listener = UnixListener::bind(path);
+        unsafe { libc::umask(umask) };
+        let listener = listener.map_err(|e| format!("{}: {}", path, e))?;
+        listener
+            .set_nonblocking(true)
+            .map_err(|e| format!("{}: {}", path, e))?;
+        info!("Control socket listening on {}.", path);
+        Ok(Listener {
+            listener,
+            path: PathBuf::from(path),
+            conns: conn::Connections::new(first),
+        })
+    }
+
+    /// Registers every pending connection with `registry`.
+    pub fn accept(&mut self, registry: &mio::Registry) {
+        loop {
+            let result = self
+                .listener
+                .accept()
+                .and_then(|(stream, _)| stream.set_nonblocking(true).map(|_| stream));
+            let stream = match result {
+                Ok(stream) => stream,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("Control socket: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = self.conns.add(registry, stream) {
+                warn!("Control socket: {}", e);
+            }
+        }
+    }
+
+    pub fn owns(&self, token: mio::Token) -> bool {
+        self.conns.owns(token)
+    }
+
+    /// Answers the connection of `token` with `handler` once its request is in.
+    pub fn ready<F>(&mut self, registry: &mio::Registry, token: mio::Token, handler: F)
+    where
+        F: FnOnce(Request) -> Response,
+    {
+        self.conns.ready(registry, token, |line| {
+            let response = match serde_json::from_str(line) {
+                Ok(request) => handler(request),
+                Err(e) => Response::Error(format!("invalid request: {}", e)),
+            };
+            let mut line = serde_json::to_vec(&response).unwrap();
+            line.push(b'\n');
+            line
+        });
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn write_line<T: serde::Serialize>(mut stream: &UnixStream, value: &T) -> Result<(), String> {
+    let mut line = serde_json::to_vec(value).map_err(|e| e.to_string())?;
+    line.push(b'\n');
+    stream.write_all(&line).map_err(|e| e.to_string())
+}
+
+/// Sends `request` to the server listening on `path` and waits for its response.
+pub fn request(path: &str, request: &Request) -> Result<Response, String> {
+    let stream = UnixStream::connect(path).map_err(|e| format!("{}: {}", path, e))?;
+    stream
+        .set_read_timeout(Some(TIMEOUT))
+        .map_err(|e| e.to_string())?;
+    write_line(&stream, request)?;
+    let mut line = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut line)
+        .map_err(|e| format!("{}: {}", path, e))?;
+    match serde_json::from_str(&line).map_err(|e| format!("{}: {}", path, e))? {
+        Response::Error(e) => Err(e),
+        response => Ok(response),
+    }
+}
+
+fn ago(now: u64, time: u64) -> String {
+    format!("{}s ago", now.saturating_sub(time))
+}
+
+fn format_table(sessions: &[SessionInfo], now: u64) -> String {
+    let mut table = format!(
+        "{:<4} {:<15} {:<24} {:<16} {:>5} {:>12} {:>12} {:>8} {:>15} {:>12}\n",
+        "ID",
+        "ADDRESS",
+        "ENDPOINT",
+        "NAME",
+        "MTU",
+        "BYTES IN",
+        "BYTES OUT",
+        "DROPPED",
+        "HANDSHAKE",
+        "LAST PACKET"
+    );
+    for s in sessions {
+        table.push_str(&format!(
+            "{:<4} {:<15} {:<24} {:<16} {:>5} {:>12} {:>12} {:>8} {:>15} {:>12}\n",
+            s.id,
+            s.address,
+            s.endpoint,
+            s.name,
+            s.mtu,
+            s.bytes_in,
+            s.bytes_out,
+            s.dropped_in + s.dropped_out,
+            ago(now, s.last_handshake),
+            s.last_packet
+                .map(|t| ago(now, t))
+                .unwrap_or_else(|| String::from("never")),
+        ));
+    }
+    table
+}
+
+/// Implements `kytan status`.
+pub fn status(path: &str, json: bool) -> Result<(), String> {
+    let mut sessions = match request(path, &Request::Status)? {
+        Response::Status(sessions) => sessions,
+        response => return Err(format!("unexpected response {:?}", response)),
+    };
+    sessions.sort_by_key(|s| s.id);
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&sessions).map_err(|e| e.to_string())?
+        );
+    } else {
+        print!("{}", format_table(&sessions, unix_time(SystemTime::now())));
+    }
+    Ok(())
+}
+
+fn format_usage(usage: &[UsageInfo]) -> String {
+    let mut table = format!(
+        "{:<24} {:>14} {:>14} {:>14} {:>14}\n",
+        "IDENTITY", "TODAY", "THIS MONTH", "BYTES IN", "BYTES OUT"
+    );
+    for u in usage {
+        table.push_str(&format!(
+            "{:<24} {:>14} {:>14} {:>14} {:>14}\n",
+            u.identity, u.today, u.this_month, u.bytes_in, u.bytes_out
+        ));
+    }
+    table
+}
+
+/// Implements `kytan usage`.
+pub fn usage(path: &str, json: bool) -> Result<(), String> {
+    let mut usage = match request(path, &Request::Usage)? {
+        Response::Usage(usage) => usage,
+        response => return Err(format!("unexpected response {:?}", response)),
+    };
+    usage.sort_by(|a, b| a.identity.cmp(&b.identity));
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&usage).map_err(|e| e.to_string())?
+        );
+    } else {
+        print!("{}", format_usage(&usage));
+    }
+    Ok(())
+}
+
+/// Implements `kytan kick`, `kytan ban` and `kytan unban`.
+pub fn command(path: &str, request: &Request) -> Result<(), String> {
+    match self::request(path, request)? {
+        Response::Done(message) => {
+            println!("{}", message);
+            Ok(())
+        }
+        response => Err(format!("unexpected response {:?}", response)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::control::*;
+    use std::thread;
+
+    fn session() -> SessionInfo {
+        SessionInfo {
+            id: 2,
+            address: String::from("10.10.10.2"),
+            endpoint: String::from("192.0.2.1:40000"),
+            name: String::from("laptop"),
+            mtu: 1380,
+            bytes_in: 100,
+            bytes_out: 200,
+            dropped_in: 0,
+            dropped_out: 3,
+            last_handshake: 1000,
+            last_packet: None,
+        }
+    }
+
+    #[test]
+    fn protocol_test() {
+        assert_eq!(
+            serde_json::to_string(&Request::Status).unwrap(),
+            r#"{"command":"status"}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Request>(r#"{"command":"kick","target":"laptop"}"#).unwrap(),
+            Request::Kick {
+                target: String::from("laptop"),
+                ban: false,
+            }
+        );
+        let response = Response::Status(vec![session()]);
+        let encoded = serde_json::to_string(&response).unwrap();
+        assert_eq!(serde_json::from_str::<Response>(&encoded).unwrap(), response);
+    }
+
+    #[test]
+    fn format_table_test() {
+        let table = format_table(&[session()], 1030);
+        let row = table.lines().nth(1).unwrap();
+        assert!(row.starts_with("2    10.10.10.2"));
+        assert!(row.contains(" 1380          100 "));
+        assert!(row.contains(" 200        3 "));
+        assert!(row.contains("30s ago"));
+        assert!(row.ends_with("never"));
+    }
+
+    #[test]
+    fn socket_test() {
+        let path = std::env::temp_dir().join(format!("kytan-control-{}", std::process::id()));
+        let path = String::from(path.to_str().unwrap());
+        let mut poll = mio::Poll::new().unwrap();
+        let mut events = mio::Events::with_capacity(16);
+        let mut listener = Listener::bind(&path, mio::Token(16)).unwrap();
+        poll.registry()
+            .register(
+                &mut mio::unix::SourceFd(&listener.as_raw_fd()),
+                mio::Token(0),
+                mio::Interest::READABLE,
+            )
+            .unwrap();
+        let client_path = path.clone();
+        let client = thread::spawn(move || request(&client_path, &Request::Status));
+        let mut served = false;
+        while !served {
+            poll.poll(&mut events, None).unwrap();
+            for event in events.iter() {
+                match event.token() {
+                    mio::Token(0) => listener.accept(poll.registry()),
+                    token => listener.ready(poll.registry(), token, |request| {
+                        assert_eq!(request, Request::Status);
+                        served = true;
+                        Response::Status(vec![session()])
+                    }),
+                }
+            }
+        }
+        assert_eq!(
+            client.join().unwrap().unwrap(),
+            Response::Status(vec![session()])
+        );
+        drop(listener);
+        assert!(!std::path::Path::new(&path).exists());
+    }
+}
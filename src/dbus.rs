@@ -0,0 +1,387 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Just enough of the D-Bus wire protocol to call methods on the system bus.
+
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+pub const SYSTEM_BUS: &str = "/run/dbus/system_bus_socket";
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_ERROR_NAME: u8 = 4;
+const FIELD_REPLY_SERIAL: u8 = 5;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SIGNATURE: u8 = 8;
+
+/// Marshals values in little-endian order. Offsets are relative to the start
+/// of the message, or of the body, which starts on an 8-byte boundary.
+#[derive(Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn align(&mut self, n: usize) {
+        self.buf.resize(self.buf.len().next_multiple_of(n), 0);
+    }
+
+    pub fn byte(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.align(4);
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, value: i32) {
+        self.u32(value as u32);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u32(value as u32);
+    }
+
+    pub fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.buf.extend_from_slice(value.as_bytes());
+        self.buf.push(0);
+    }
+
+    pub fn signature(&mut self, value: &str) {
+        self.buf.push(value.len() as u8);
+        self.buf.extend_from_slice(value.as_bytes());
+        self.buf.push(0);
+    }
+
+    /// Starts a struct, which is aligned to 8 bytes.
+    pub fn structure(&mut self) {
+        self.align(8);
+    }
+
+    /// Writes an array whose elements, written by `elements`, are aligned to
+    /// `align` bytes.
+    pub fn array<F>(&mut self, align: usize, elements: F)
+    where
+        F: FnOnce(&mut Writer),
+    {
+        self.u32(0);
+        let len_at = self.buf.len() - 4;
+        self.align(align);
+        let start = self.buf.len();
+        elements(self);
+        let len = (self.buf.len() - start) as u32;
+        self.buf[len_at..len_at + 4].copy_from_slice(&len.to_le_bytes());
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Reads the header fields of a message, in either byte order.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn align(&mut self, n: usize) {
+        self.pos = self.pos.next_multiple_of(n);
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or("truncated D-Bus message")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        self.align(4);
+        let b = self.take(4)?;
+        let bytes = [b[0], b[1], b[2], b[3]];
+        Ok(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len + 1)?;
+        Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+    }
+
+    fn signature(&mut self) -> Result<String, String> {
+        let len = self.byte()? as usize;
+        let bytes = self.take(len + 1)?;
+        Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+    }
+}
+
+fn field(w: &mut Writer, code: u8, signature: &str, value: &str) {
+    w.structure();
+    w.byte(code);
+    w.signature(signature);
+    match signature {
+        "g" => w.signature(value),
+        _ => w.string(value),
+    }
+}
+
+/// A method call with a `body` already marshalled according to `signature`.
+pub struct Call<'a> {
+    pub destination: &'a str,
+    pub path: &'a str,
+    pub interface: &'a str,
+    pub member: &'a str,
+    pub signature: &'a str,
+    pub body: Vec<u8>,
+}
+
+impl<'a> Call<'a> {
+    fn encode(&self, serial: u32) -> Vec<u8> {
+        let mut w = Writer::default();
+        w.byte(b'l');
+        w.byte(METHOD_CALL);
+        w.byte(0);
+        w.byte(1);
+        w.u32(self.body.len() as u32);
+        w.u32(serial);
+        w.array(8, |w| {
+            field(w, FIELD_PATH, "o", self.path);
+            field(w, FIELD_INTERFACE, "s", self.interface);
+            field(w, FIELD_MEMBER, "s", self.member);
+            field(w, FIELD_DESTINATION, "s", self.destination);
+            if !self.signature.is_empty() {
+                field(w, FIELD_SIGNATURE, "g", self.signature);
+            }
+        });
+        w.structure();
+        w.buf.extend_from_slice(&self.body);
+        w.buf
+    }
+}
+
+/// What the bus sent back: a reply, an error or a message to skip.
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Return(u32),
+    Error(u32, String),
+    Other,
+}
+
+/// Parses a message read whole from the bus, returning the serial of the
+/// call it answers.
+fn parse(message: &[u8]) -> Result<Reply, String> {
+    let big_endian = match message.first() {
+        Some(b'l') => false,
+        Some(b'B') => true,
+        _ => return Err(String::from("invalid D-Bus message")),
+    };
+    let mut r = Reader {
+        buf: message,
+        pos: 12,
+        big_endian,
+    };
+    let fields_len = r.u32()? as usize;
+    let end = 16 + fields_len;
+    let (mut reply_serial, mut error, mut signature) = (None, None, String::new());
+    while r.pos < end {
+        r.align(8);
+        let code = r.byte()?;
+        match (code, r.signature()?.as_str()) {
+            (FIELD_REPLY_SERIAL, "u") => reply_serial = Some(r.u32()?),
+            (FIELD_ERROR_NAME, "s") => error = Some(r.string()?),
+            (FIELD_SIGNATURE, "g") => signature = r.signature()?,
+            (_, "s") | (_, "o") => {
+                r.string()?;
+            }
+            (_, "g") => {
+                r.signature()?;
+            }
+            (_, "u") => {
+                r.u32()?;
+            }
+            (_, other) => return Err(format!("unexpected D-Bus header field type {}", other)),
+        }
+    }
+    let serial = match reply_serial {
+        Some(serial) => serial,
+        None => return Ok(Reply::Other),
+    };
+    match message[1] {
+        METHOD_RETURN => Ok(Reply::Return(serial)),
+        ERROR => {
+            let mut name = error.unwrap_or_default();
+            if signature.starts_with('s') {
+                r.align(8);
+                name = format!("{}: {}", name, r.string()?);
+            }
+            Ok(Reply::Error(serial, name))
+        }
+        _ => Ok(Reply::Other),
+    }
+}
+
+/// A connection to a message bus.
+pub struct Bus {
+    stream: UnixStream,
+    serial: u32,
+}
+
+impl Bus {
+    pub fn system() -> Result<Bus, String> {
+        Bus::connect(Path::new(SYSTEM_BUS))
+    }
+
+    pub fn connect(path: &Path) -> Result<Bus, String> {
+        let error = |e: std::io::Error| format!("{}: {}", path.display(), e);
+        let mut stream = UnixStream::connect(path).map_err(error)?;
+        stream.set_read_timeout(Some(TIMEOUT)).map_err(error)?;
+        // The bus knows who we are from the socket; the uid only has to match.
+        let uid = unsafe { libc::getuid() }.to_string();
+        let hex: String = uid.bytes().map(|b| format!("{:02x}", b)).collect();
+        stream
+            .write_all(format!("\0AUTH EXTERNAL {}\r\n", hex).as_bytes())
+            .map_err(error)?;
+        let mut line = Vec::new();
+        while !line.ends_with(b"\r\n") {
+            let mut byte = [0u8];
+            stream.read_exact(&mut byte).map_err(error)?;
+            line.push(byte[0]);
+        }
+        if !line.starts_with(b"OK ") {
+            let line = String::from_utf8_lossy(&line);
+            return Err(format!("{}: authentication failed: {}", path.display(), line.trim()));
+        }
+        stream.write_all(b"BEGIN\r\n").map_err(error)?;
+        let mut bus = Bus { stream, serial: 0 };
+        bus.call(&Call {
+            destination: "org.freedesktop.DBus",
+            path: "/org/freedesktop/DBus",
+            interface: "org.freedesktop.DBus",
+            member: "Hello",
+            signature: "",
+            body: Vec::new(),
+        })?;
+        Ok(bus)
+    }
+
+    /// Calls a method and waits for it to return, ignoring its return value.
+    pub fn call(&mut self, call: &Call) -> Result<(), String> {
+        self.serial += 1;
+        let error = |e: std::io::Error| format!("{}.{}: {}", call.interface, call.member, e);
+        self.stream.write_all(&call.encode(self.serial)).map_err(error)?;
+        loop {
+            let mut fixed = [0u8; 16];
+            self.stream.read_exact(&mut fixed).map_err(error)?;
+            let mut r = Reader {
+                buf: &fixed,
+                pos: 4,
+                big_endian: fixed[0] == b'B',
+            };
+            let body_len = r.u32()? as usize;
+            r.pos = 12;
+            let fields_len = r.u32()? as usize;
+            let len = (16 + fields_len).next_multiple_of(8) + body_len;
+            let mut message = fixed.to_vec();
+            message.resize(len, 0);
+            self.stream.read_exact(&mut message[16..]).map_err(error)?;
+            match parse(&message)? {
+                Reply::Return(serial) if serial == self.serial => return Ok(()),
+                Reply::Error(serial, name) if serial == self.serial => {
+                    return Err(format!("{}.{}: {}", call.interface, call.member, name))
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dbus::*;
+
+    #[test]
+    fn writer_test() {
+        let mut w = Writer::default();
+        w.i32(3);
+        w.array(8, |w| {
+            w.structure();
+            w.i32(2);
+            w.array(1, |w| w.byte(10));
+        });
+        assert_eq!(
+            w.into_bytes(),
+            vec![3, 0, 0, 0, 9, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 10]
+        );
+    }
+
+    #[test]
+    fn parse_test() {
+        let call = Call {
+            destination: "org.freedesktop.DBus",
+            path: "/org/freedesktop/DBus",
+            interface: "org.freedesktop.DBus",
+            member: "Hello",
+            signature: "s",
+            body: Vec::new(),
+        };
+        let message = call.encode(7);
+        assert_eq!(message.len() % 8, 0);
+        assert_eq!(parse(&message).unwrap(), Reply::Other);
+
+        let mut w = Writer::default();
+        w.byte(b'l');
+        w.byte(ERROR);
+        w.byte(0);
+        w.byte(1);
+        w.u32(12);
+        w.u32(9);
+        w.array(8, |w| {
+            field(w, FIELD_ERROR_NAME, "s", "org.example.Failed");
+            w.structure();
+            w.byte(FIELD_REPLY_SERIAL);
+            w.signature("u");
+            w.u32(7);
+            field(w, FIELD_SIGNATURE, "g", "s");
+        });
+        w.structure();
+        w.string("no such");
+        assert_eq!(
+            parse(&w.into_bytes()).unwrap(),
+            Reply::Error(7, String::from("org.example.Failed: no such"))
+        );
+    }
+}
@@ -17,7 +17,12 @@ use libc::*;
 use std::os::unix::io::{RawFd, AsRawFd};
 use std::io::{Write, Read};
 
-const MTU: &'static str = "1380";
+/// The MTU of the TUN device unless configured otherwise.
+pub const DEFAULT_MTU: u32 = 1380;
+// The MTUs accepted. Packets of up to `MAX_MTU` bytes still fit into the
+// buffers once compressed, encrypted and framed.
+pub const MIN_MTU: u32 = 576;
+pub const MAX_MTU: u32 = 1500;
 
 #[cfg(target_os = "linux")]
 use std::path;
@@ -26,6 +31,8 @@ const IFNAMSIZ: usize = 16;
 #[cfg(target_os = "linux")]
 const IFF_TUN: c_short = 0x0001;
 #[cfg(target_os = "linux")]
+const IFF_TAP: c_short = 0x0002;
+#[cfg(target_os = "linux")]
 const IFF_NO_PI: c_short = 0x1000;
 #[cfg(all(target_os = "linux", target_env = "musl"))]
 const TUNSETIFF: c_int = 0x400454ca; // TODO: use _IOW('T', 202, int)
@@ -87,20 +94,39 @@ impl AsRawFd for Tun {
     }
 }
 
+/// The name of the device created unless configured otherwise, where `%d` is
+/// replaced by the first free number.
+pub fn default_name(tap: bool) -> &'static str {
+    if cfg!(target_os = "macos") {
+        "utun%d"
+    } else if tap {
+        "tap%d"
+    } else {
+        "tun%d"
+    }
+}
+
 impl Tun {
+    /// Creates the device `name`, which may contain `%d`, carrying Ethernet
+    /// frames if `tap`.
     #[cfg(target_os = "linux")]
-    pub fn create(name: u8) -> Result<Tun, io::Error> {
+    pub fn create(name: &str, tap: bool) -> Result<Tun, io::Error> {
+        if name.is_empty() || name.len() >= IFNAMSIZ {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid interface name: {:?}", name),
+            ));
+        }
         let path = path::Path::new("/dev/net/tun");
         let file = fs::OpenOptions::new().read(true).write(true).open(&path)?;
 
         let mut req = ioctl_flags_data {
             ifr_name: {
                 let mut buffer = [0u8; IFNAMSIZ];
-                let full_name = format!("tun{}", name);
-                buffer[..full_name.len()].clone_from_slice(full_name.as_bytes());
+                buffer[..name.len()].clone_from_slice(name.as_bytes());
                 buffer
             },
-            ifr_flags: IFF_TUN | IFF_NO_PI,
+            ifr_flags: if tap { IFF_TAP } else { IFF_TUN } | IFF_NO_PI,
         };
 
         let res = unsafe { ioctl(file.as_raw_fd(), TUNSETIFF, &mut req) }; // TUNSETIFF
@@ -116,8 +142,25 @@ impl Tun {
         Ok(tun)
     }
 
+    /// Only `utun<N>` and `utun%d` can be created on macOS.
     #[cfg(target_os = "macos")]
-    pub fn create(name: u8) -> Result<Tun, io::Error> {
+    pub fn create(name: &str, tap: bool) -> Result<Tun, io::Error> {
+        if tap {
+            return Err(io::Error::new(io::ErrorKind::Other, "TAP devices are not supported"));
+        }
+        // Unit 0 lets the kernel pick the first free number.
+        let unit = match name {
+            "utun%d" => 0,
+            _ => match name.strip_prefix("utun").and_then(|n| n.parse::<u32>().ok()) {
+                Some(n) => n + 1,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid interface name: {:?}", name),
+                    ))
+                }
+            },
+        };
         let handle = {
             let fd = unsafe { socket(PF_SYSTEM, SOCK_DGRAM, SYSPROTO_CONTROL) };
             if fd < 0 {
@@ -146,11 +189,11 @@ impl Tun {
             sc_len: mem::size_of::<sockaddr_ctl>() as u8,
             sc_family: AF_SYSTEM,
             ss_sysaddr: AF_SYS_CONTROL,
-            sc_unit: name as u32 + 1,
+            sc_unit: unit,
             sc_reserved: [0; 5],
         };
 
-        // If connect() is successful, a tun%d device will be created, where "%d"
+        // If connect() is successful, a utun%d device will be created, where "%d"
         // is our sc_unit-1
         let res = unsafe {
             let addr_ptr = &addr as *const sockaddr_ctl;
@@ -199,7 +242,7 @@ impl Tun {
         &self.if_name
     }
 
-    pub fn up(&self, self_id: u8) {
+    pub fn up(&self, self_id: u8, mtu: u32) {
         let mut status = if cfg!(target_os = "linux") {
             process::Command::new("ifconfig")
                 .arg(self.if_name.clone())
@@ -223,7 +266,7 @@ impl Tun {
             process::Command::new("ifconfig")
                 .arg(self.if_name.clone())
                 .arg("mtu")
-                .arg(MTU)
+                .arg(mtu.to_string())
                 .arg("up")
                 .status()
                 .unwrap()
@@ -231,7 +274,7 @@ impl Tun {
             process::Command::new("ifconfig")
                 .arg(self.if_name.clone())
                 .arg("mtu")
-                .arg(MTU)
+                .arg(mtu.to_string())
                 .arg("up")
                 .status()
                 .unwrap()
@@ -241,6 +284,21 @@ impl Tun {
 
         assert!(status.success());
     }
+
+    /// Changes the MTU of the device once it is up.
+    pub fn set_mtu(&self, mtu: u32) -> Result<(), String> {
+        let status = process::Command::new("ifconfig")
+            .arg(&self.if_name)
+            .arg("mtu")
+            .arg(mtu.to_string())
+            .status()
+            .map_err(|e| format!("ifconfig: {}", e))?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(format!("ifconfig {} mtu {}: {}", self.if_name, mtu, status))
+        }
+    }
 }
 
 impl Read for Tun {
@@ -299,7 +357,7 @@ mod tests {
     fn create_tun_test() {
         assert!(utils::is_root());
 
-        let tun = Tun::create(10).unwrap();
+        let tun = Tun::create("tun10", false).unwrap();
         let name = tun.name();
 
         let output = process::Command::new("ifconfig")
@@ -308,6 +366,12 @@ mod tests {
             .expect("failed to create tun device");
         assert!(output.status.success());
 
-        tun.up(1);
+        tun.up(1, DEFAULT_MTU);
+
+        if cfg!(target_os = "linux") {
+            let tap = Tun::create("kytan%d", true).unwrap();
+            assert!(tap.name().starts_with("kytan"));
+            assert!(Tun::create("kytan-too-long-name", false).is_err());
+        }
     }
 }
@@ -0,0 +1,404 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::dbus;
+use crate::journal;
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const RESOLV_CONF_BACKUP: &str = "/etc/resolv.conf.kytan";
+const RESOLVED_RUNTIME_DIR: &str = "/run/systemd/resolve";
+
+/// DNS settings pushed from the server to its clients.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct Settings {
+    pub servers: Vec<IpAddr>,
+    pub search: Vec<String>,
+    /// If non-empty, only names under these domains are resolved through the tunnel.
+    pub split: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    Auto,
+    Resolved,
+    Resolvconf,
+    File,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Backend, String> {
+        match s {
+            "auto" => Ok(Backend::Auto),
+            "resolved" => Ok(Backend::Resolved),
+            "resolvconf" => Ok(Backend::Resolvconf),
+            "file" => Ok(Backend::File),
+            _ => Err(format!("unknown dns backend: {}", s)),
+        }
+    }
+}
+
+impl Backend {
+    pub fn detect() -> Backend {
+        if cfg!(target_os = "linux") {
+            if Path::new(RESOLVED_RUNTIME_DIR).is_dir() && Path::new(dbus::SYSTEM_BUS).exists() {
+                return Backend::Resolved;
+            }
+            if command_exists("resolvconf") {
+                return Backend::Resolvconf;
+            }
+        }
+        Backend::File
+    }
+}
+
+pub struct DnsConfig {
+    backend: Backend,
+    if_name: String,
+    path: PathBuf,
+    backup: PathBuf,
+}
+
+impl DnsConfig {
+    pub fn create(
+        backend: Backend,
+        if_name: &str,
+        settings: &Settings,
+    ) -> Result<DnsConfig, String> {
+        DnsConfig::apply(
+            backend,
+            if_name,
+            Path::new(RESOLV_CONF),
+            Path::new(RESOLV_CONF_BACKUP),
+            settings,
+        )
+    }
+
+    fn apply(
+        backend: Backend,
+        if_name: &str,
+        path: &Path,
+        backup: &Path,
+        settings: &Settings,
+    ) -> Result<DnsConfig, String> {
+        let backend = match backend {
+            Backend::Auto => Backend::detect(),
+            backend => backend,
+        };
+        info!("Configuring DNS for {} using the {:?} backend.", if_name, backend);
+        if !settings.split.is_empty() && backend != Backend::Resolved {
+            warn!("Split DNS requires systemd-resolved. Using the tunnel DNS for all domains.");
+        }
+        if settings.servers.is_empty() && backend == Backend::File {
+            return Err(format!("no DNS servers to write to {}", path.display()));
+        }
+        let dns = DnsConfig {
+            backend,
+            if_name: String::from(if_name),
+            path: path.to_path_buf(),
+            backup: backup.to_path_buf(),
+        };
+        // Journaled first so that a crash halfway through is undone too.
+        journal::record(dns.change());
+        let result = match backend {
+            Backend::Resolved => set_resolved(if_name, settings),
+            Backend::Resolvconf => set_resolvconf(if_name, settings),
+            Backend::File | Backend::Auto => set_file_backed_up(path, backup, settings),
+        };
+        match result {
+            Ok(()) => Ok(dns),
+            Err(e) => {
+                // Dropping undoes whatever part of it was applied.
+                drop(dns);
+                Err(e)
+            }
+        }
+    }
+
+    fn change(&self) -> journal::Change {
+        journal::Change::Dns {
+            backend: self.backend,
+            if_name: self.if_name.clone(),
+        }
+    }
+}
+
+impl Drop for DnsConfig {
+    fn drop(&mut self) {
+        let result = match self.backend {
+            Backend::Resolved => revert_resolved(&self.if_name),
+            Backend::Resolvconf => delete_resolvconf(&self.if_name),
+            Backend::File | Backend::Auto => restore_file(&self.path, &self.backup),
+        };
+        match result {
+            Ok(()) => journal::forget(&self.change()),
+            Err(e) => warn!("Unable to restore DNS configuration: {}", e),
+        }
+    }
+}
+
+/// Undoes the DNS configuration of a kytan process that did not exit cleanly.
+pub fn revert(backend: Backend, if_name: &str) -> Result<(), String> {
+    match backend {
+        Backend::Resolved => revert_resolved(if_name),
+        Backend::Resolvconf => delete_resolvconf(if_name),
+        Backend::File | Backend::Auto => {
+            restore_file(Path::new(RESOLV_CONF), Path::new(RESOLV_CONF_BACKUP))
+        }
+    }
+}
+
+fn command_exists(name: &str) -> bool {
+    Command::new("bash")
+        .arg("-c")
+        .arg(format!("command -v {}", name))
+        .stdout(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+fn run(cmd: &mut Command) -> Result<(), String> {
+    let status = cmd.status().map_err(|e| format!("{:?}: {}", cmd, e))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("{:?}: {}", cmd, status))
+    }
+}
+
+fn resolv_conf(settings: &Settings) -> String {
+    let mut conf = String::new();
+    for server in &settings.servers {
+        conf.push_str(&format!("nameserver {}\n", server));
+    }
+    if !settings.search.is_empty() {
+        conf.push_str(&format!("search {}\n", settings.search.join(" ")));
+    }
+    conf
+}
+
+fn resolved_domains(settings: &Settings) -> Vec<String> {
+    let mut domains = settings.search.clone();
+    if settings.split.is_empty() {
+        // "~." routes every query through this link, like rewriting resolv.conf.
+        domains.push(String::from("~."));
+    } else {
+        domains.extend(settings.split.iter().map(|d| format!("~{}", d)));
+    }
+    domains
+}
+
+fn if_index(if_name: &str) -> Result<i32, String> {
+    let name = CString::new(if_name).map_err(|e| e.to_string())?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(format!("{}: {}", if_name, io::Error::last_os_error())),
+        index => Ok(index as i32),
+    }
+}
+
+fn resolved<'a>(member: &'a str, signature: &'a str, body: dbus::Writer) -> dbus::Call<'a> {
+    dbus::Call {
+        destination: "org.freedesktop.resolve1",
+        path: "/org/freedesktop/resolve1",
+        interface: "org.freedesktop.resolve1.Manager",
+        member,
+        signature,
+        body: body.into_bytes(),
+    }
+}
+
+fn set_resolved(if_name: &str, settings: &Settings) -> Result<(), String> {
+    let index = if_index(if_name)?;
+    let mut bus = dbus::Bus::system()?;
+
+    let mut servers = dbus::Writer::default();
+    servers.i32(index);
+    servers.array(8, |w| {
+        for server in &settings.servers {
+            let (family, octets) = match *server {
+                IpAddr::V4(addr) => (libc::AF_INET, addr.octets().to_vec()),
+                IpAddr::V6(addr) => (libc::AF_INET6, addr.octets().to_vec()),
+            };
+            w.structure();
+            w.i32(family);
+            w.array(1, |w| octets.iter().for_each(|&byte| w.byte(byte)));
+        }
+    });
+    bus.call(&resolved("SetLinkDNS", "ia(iay)", servers))?;
+
+    let mut domains = dbus::Writer::default();
+    domains.i32(index);
+    domains.array(8, |w| {
+        for domain in resolved_domains(settings) {
+            // A leading "~" marks a domain only used to route queries.
+            let (name, routing_only) = match domain.strip_prefix('~') {
+                Some(name) => (name, true),
+                None => (domain.as_str(), false),
+            };
+            w.structure();
+            w.string(name);
+            w.bool(routing_only);
+        }
+    });
+    bus.call(&resolved("SetLinkDomains", "ia(sb)", domains))?;
+
+    let mut default_route = dbus::Writer::default();
+    default_route.i32(index);
+    default_route.bool(settings.split.is_empty());
+    bus.call(&resolved("SetLinkDefaultRoute", "ib", default_route))
+}
+
+fn revert_resolved(if_name: &str) -> Result<(), String> {
+    info!("Reverting DNS configuration of {}.", if_name);
+    let mut link = dbus::Writer::default();
+    link.i32(if_index(if_name)?);
+    dbus::Bus::system()?.call(&resolved("RevertLink", "i", link))
+}
+
+fn set_resolvconf(if_name: &str, settings: &Settings) -> Result<(), String> {
+    let mut child = Command::new("resolvconf")
+        .arg("-a")
+        .arg(format!("{}.kytan", if_name))
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("resolvconf: {}", e))?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(resolv_conf(settings).as_bytes())
+        .map_err(|e| format!("resolvconf: {}", e))?;
+    let status = child.wait().map_err(|e| format!("resolvconf: {}", e))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("resolvconf: {}", status))
+    }
+}
+
+fn delete_resolvconf(if_name: &str) -> Result<(), String> {
+    info!("Removing resolvconf entry of {}.", if_name);
+    run(Command::new("resolvconf")
+        .arg("-d")
+        .arg(format!("{}.kytan", if_name)))
+}
+
+fn restore_file(path: &Path, backup: &Path) -> Result<(), String> {
+    if fs::symlink_metadata(backup).is_err() {
+        return Ok(());
+    }
+    info!("Restoring {} from {}.", path.display(), backup.display());
+    fs::rename(backup, path).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn set_file_backed_up(path: &Path, backup: &Path, settings: &Settings) -> Result<(), String> {
+    // A backup left behind means the previous run never restored it.
+    restore_file(path, backup)?;
+    // Hard link the current entry (even if it is a symlink) so that it
+    // can be moved back into place atomically.
+    fs::hard_link(path, backup).map_err(|e| format!("{}: {}", backup.display(), e))?;
+    info!("Backed up {} to {}.", path.display(), backup.display());
+    set_file(path, settings)
+}
+
+fn set_file(path: &Path, settings: &Settings) -> Result<(), String> {
+    info!("Setting DNS servers to {:?}.", settings.servers);
+    let tmp = path.with_extension("kytan.tmp");
+    fs::write(&tmp, resolv_conf(settings))
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|e| {
+            let _ = fs::remove_file(&tmp);
+            format!("{}: {}", path.display(), e)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns::*;
+
+    fn settings(servers: &[&str], search: &[&str], split: &[&str]) -> Settings {
+        Settings {
+            servers: servers.iter().map(|s| s.parse().unwrap()).collect(),
+            search: search.iter().map(|s| s.to_string()).collect(),
+            split: split.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn resolv_conf_test() {
+        assert_eq!(
+            resolv_conf(&settings(&["8.8.8.8"], &[], &[])),
+            "nameserver 8.8.8.8\n"
+        );
+        assert_eq!(
+            resolv_conf(&settings(
+                &["8.8.8.8", "2001:4860:4860::8888"],
+                &["example.com", "corp"],
+                &[]
+            )),
+            "nameserver 8.8.8.8\nnameserver 2001:4860:4860::8888\nsearch example.com corp\n"
+        );
+    }
+
+    #[test]
+    fn resolved_domains_test() {
+        assert_eq!(
+            resolved_domains(&settings(&["8.8.8.8"], &["example.com"], &[])),
+            vec!["example.com", "~."]
+        );
+        assert_eq!(
+            resolved_domains(&settings(&["10.0.0.53"], &[], &["corp.example"])),
+            vec!["~corp.example"]
+        );
+    }
+
+    #[test]
+    fn backend_from_str_test() {
+        assert_eq!("auto".parse::<Backend>().unwrap(), Backend::Auto);
+        assert_eq!("resolved".parse::<Backend>().unwrap(), Backend::Resolved);
+        assert!("dbus".parse::<Backend>().is_err());
+    }
+
+    #[test]
+    fn file_backend_test() {
+        let dir = std::env::temp_dir().join(format!("kytan-dns-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("resolv.conf");
+        let backup = dir.join("resolv.conf.kytan");
+        fs::write(&path, "nameserver 1.1.1.1\n").unwrap();
+        let dns = |servers: &[&str]| {
+            DnsConfig::apply(Backend::File, "", &path, &backup, &settings(servers, &[], &[]))
+        };
+        assert!(dns(&[]).is_err());
+        {
+            let _dns = dns(&["8.8.8.8"]).unwrap();
+            assert_eq!(fs::read_to_string(&path).unwrap(), "nameserver 8.8.8.8\n");
+            assert!(backup.exists());
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "nameserver 1.1.1.1\n");
+        assert!(!backup.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
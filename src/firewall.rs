@@ -0,0 +1,232 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::journal;
+use crate::utils::Ipv4Net;
+use log::{info, warn};
+use std::net::{IpAddr, SocketAddr};
+use std::process::{Command, Stdio};
+
+const CHAIN: &str = "KYTAN";
+
+/// Networks that stay reachable outside the tunnel besides the attached
+/// ones: link-local ranges, and broadcasts and multicasts for DHCP and NDP.
+const LOCAL_V4: [&str; 2] = ["169.254.0.0/16", "255.255.255.255/32"];
+const LOCAL_V6: [&str; 2] = ["fe80::/10", "ff00::/8"];
+
+fn iptables(v6: bool, args: &[&str]) -> Result<(), String> {
+    let program = if v6 { "ip6tables" } else { "iptables" };
+    let status = Command::new(program)
+        .arg("-w")
+        .args(args)
+        .status()
+        .map_err(|e| format!("{}: {}", program, e))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("{} {}: {}", program, args.join(" "), status))
+    }
+}
+
+/// Runs a command whose failure is expected, e.g. to test for a rule.
+fn succeeds(v6: bool, args: &[&str]) -> bool {
+    let program = if v6 { "ip6tables" } else { "iptables" };
+    Command::new(program)
+        .arg("-w")
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// The networks directly attached to interfaces other than `if_name`, from
+/// the output of `ip route show`: routes without a gateway.
+fn attached(routes: &str, if_name: &str) -> Vec<String> {
+    routes
+        .lines()
+        .filter_map(|line| {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let dev = words.iter().position(|&word| word == "dev")?;
+            if words.contains(&"via") || words.get(dev + 1) == Some(&if_name) {
+                return None;
+            }
+            let net = *words.first()?;
+            // Skips default, unreachable and other route types.
+            net.split('/').next()?.parse::<IpAddr>().ok()?;
+            Some(String::from(net))
+        })
+        .collect()
+}
+
+/// The LAN prefixes of the host, e.g. `192.168.1.0/24`.
+fn lan(if_name: &str, v6: bool) -> Result<Vec<String>, String> {
+    let family = if v6 { "-6" } else { "-4" };
+    let output = Command::new("ip")
+        .args([family, "route", "show", "table", "main"])
+        .output()
+        .map_err(|e| format!("ip: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("ip {} route show: {}", family, output.status));
+    }
+    Ok(attached(&String::from_utf8_lossy(&output.stdout), if_name))
+}
+
+/// The rules of the chain, each to be appended with `-A KYTAN`.
+fn rules(if_name: &str, server: SocketAddr, allowed: &[String], v6: bool) -> Vec<Vec<String>> {
+    let mut rules = vec![
+        vec![String::from("-o"), String::from("lo")],
+        vec![String::from("-o"), String::from(if_name)],
+    ];
+    if server.is_ipv6() == v6 {
+        rules.push(vec![
+            String::from("-d"),
+            server.ip().to_string(),
+            String::from("-p"),
+            String::from("udp"),
+            String::from("--dport"),
+            server.port().to_string(),
+        ]);
+    }
+    let local: &[&str] = if v6 { &LOCAL_V6 } else { &LOCAL_V4 };
+    for net in local {
+        rules.push(vec![String::from("-d"), String::from(*net)]);
+    }
+    for net in allowed {
+        rules.push(vec![String::from("-d"), net.clone()]);
+    }
+    for rule in &mut rules {
+        rule.push(String::from("-j"));
+        rule.push(String::from("ACCEPT"));
+    }
+    rules.push(vec![String::from("-j"), String::from("REJECT")]);
+    rules
+}
+
+/// Blocks all outgoing traffic except through the tunnel, to the server and
+/// to the networks attached to the host when it is created and `allowed`,
+/// for both IPv4 and IPv6. The rules live in a chain of
+/// their own that is removed when dropped, or by `kytan unblock` if kytan did
+/// not exit cleanly. Linux only.
+pub struct KillSwitch;
+
+impl KillSwitch {
+    pub fn create(
+        if_name: &str,
+        server: SocketAddr,
+        allowed: &[Ipv4Net],
+    ) -> Result<KillSwitch, String> {
+        if !cfg!(target_os = "linux") {
+            return Err(String::from("The kill switch is only supported on Linux."));
+        }
+        if remove()? {
+            warn!("Replaced a kill switch left behind by a previous run.");
+        }
+        info!("Enabling kill switch.");
+        for &v6 in &[false, true] {
+            let result = lan(if_name, v6).and_then(|mut nets| {
+                if !v6 {
+                    nets.extend(allowed.iter().map(|net| net.to_string()));
+                }
+                info!("Kill switch allows {}.", nets.join(", "));
+                install(if_name, server, &nets, v6)
+            });
+            match result {
+                Ok(()) => {}
+                // Hosts without IPv6 support are still protected over IPv4.
+                Err(e) if v6 => {
+                    warn!("Kill switch does not block IPv6: {}", e);
+                    let _ = remove_family(true);
+                }
+                Err(e) => {
+                    let _ = remove();
+                    return Err(e);
+                }
+            }
+        }
+        journal::record(journal::Change::KillSwitch);
+        Ok(KillSwitch)
+    }
+}
+
+impl Drop for KillSwitch {
+    fn drop(&mut self) {
+        info!("Disabling kill switch.");
+        match remove() {
+            Ok(_) => journal::forget(&journal::Change::KillSwitch),
+            Err(e) => warn!("Unable to disable kill switch: {}. Run `kytan unblock`.", e),
+        }
+    }
+}
+
+fn install(if_name: &str, server: SocketAddr, allowed: &[String], v6: bool) -> Result<(), String> {
+    iptables(v6, &["-N", CHAIN])?;
+    for rule in rules(if_name, server, allowed, v6) {
+        let mut args = vec!["-A", CHAIN];
+        args.extend(rule.iter().map(|arg| arg.as_str()));
+        iptables(v6, &args)?;
+    }
+    iptables(v6, &["-I", "OUTPUT", "-j", CHAIN])
+}
+
+/// Removes the kill switch. Returns whether there was one.
+pub fn remove() -> Result<bool, String> {
+    let removed = remove_family(false)?;
+    Ok(remove_family(true)? || removed)
+}
+
+fn remove_family(v6: bool) -> Result<bool, String> {
+    if !succeeds(v6, &["-n", "-L", CHAIN]) {
+        return Ok(false);
+    }
+    while succeeds(v6, &["-D", "OUTPUT", "-j", CHAIN]) {}
+    iptables(v6, &["-F", CHAIN])?;
+    iptables(v6, &["-X", CHAIN])?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::firewall::*;
+
+    #[test]
+    fn attached_test() {
+        let routes = "default via 192.168.1.1 dev eth0 proto dhcp metric 100\n\
+                      10.10.10.0/24 dev tun0 proto kernel scope link src 10.10.10.2\n\
+                      192.168.1.0/24 dev eth0 proto kernel scope link src 192.168.1.5\n\
+                      203.0.113.1 via 192.168.1.1 dev eth0\n\
+                      unreachable 198.51.100.0/24 metric 1024\n\
+                      default dev ppp0 scope link\n";
+        assert_eq!(attached(routes, "tun0"), vec!["192.168.1.0/24"]);
+        let routes = "fd00:1::/64 dev eth0 proto kernel metric 256 pref medium\n\
+                      fe80::/64 dev eth0 proto kernel metric 256 pref medium\n";
+        assert_eq!(attached(routes, "tun0"), vec!["fd00:1::/64", "fe80::/64"]);
+    }
+
+    #[test]
+    fn rules_test() {
+        let server = "203.0.113.1:9527".parse().unwrap();
+        let allowed = [String::from("198.51.100.0/24")];
+        let v4 = rules("tun0", server, &allowed, false);
+        assert_eq!(v4[1].join(" "), "-o tun0 -j ACCEPT");
+        assert_eq!(v4[2].join(" "), "-d 203.0.113.1 -p udp --dport 9527 -j ACCEPT");
+        assert!(v4.iter().any(|rule| rule.join(" ") == "-d 198.51.100.0/24 -j ACCEPT"));
+        assert!(!v4.iter().any(|rule| rule.join(" ") == "-d 10.0.0.0/8 -j ACCEPT"));
+        assert_eq!(v4.last().unwrap().join(" "), "-j REJECT");
+        let v6 = rules("tun0", server, &[], true);
+        assert!(!v6.iter().any(|rule| rule.contains(&String::from("203.0.113.1"))));
+        assert_eq!(v6.len(), 2 + LOCAL_V6.len() + 1);
+    }
+}
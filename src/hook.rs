@@ -0,0 +1,237 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use log::{info, warn};
+use std::collections::HashMap;
+use std::process::{Child, Command};
+
+/// What a hook script is told about the tunnel, as `KYTAN_*` variables.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Env {
+    pub interface: String,
+    /// The tunnel address of the client.
+    pub address: String,
+    /// The other end of the tunnel: the server for clients, the client for the server.
+    pub endpoint: String,
+    pub identity: String,
+}
+
+fn command(script: &str, event: &str, env: &Env, reason: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(script)
+        .env("KYTAN_EVENT", event)
+        .env("KYTAN_INTERFACE", &env.interface)
+        .env("KYTAN_ADDRESS", &env.address)
+        .env("KYTAN_ENDPOINT", &env.endpoint)
+        .env("KYTAN_IDENTITY", &env.identity)
+        .env("KYTAN_REASON", reason);
+    cmd
+}
+
+/// Runs `script` and waits for it to finish.
+pub fn run(script: &str, event: &str, env: &Env) -> Result<(), String> {
+    info!("Running {} hook: {}", event, script);
+    let status = command(script, event, env, "")
+        .status()
+        .map_err(|e| format!("{} hook: {}", event, e))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("{} hook: {}", event, status))
+    }
+}
+
+/// Hooks of the server that may run at once. More are skipped until some
+/// have finished.
+const MAX_RUNNING: usize = 32;
+
+/// Starts `script` without waiting for it, so that the server keeps
+/// forwarding packets while it runs.
+fn spawn(
+    children: &mut Vec<(Child, &'static str)>,
+    script: &str,
+    event: &'static str,
+    env: &Env,
+    reason: &str,
+) {
+    reap(children);
+    if children.len() >= MAX_RUNNING {
+        warn!("Skipped {} hook: {} hooks are still running.", event, children.len());
+        return;
+    }
+    info!("Running {} hook: {}", event, script);
+    match command(script, event, env, reason).spawn() {
+        Ok(child) => children.push((child, event)),
+        Err(e) => warn!("{} hook: {}", event, e),
+    }
+}
+
+/// Collects the hooks that have finished.
+fn reap(children: &mut Vec<(Child, &'static str)>) {
+    children.retain_mut(|(child, event)| match child.try_wait() {
+        Ok(None) => true,
+        Ok(Some(status)) => {
+            if !status.success() {
+                warn!("{} hook: {}", event, status);
+            }
+            false
+        }
+        Err(e) => {
+            warn!("{} hook: {}", event, e);
+            false
+        }
+    });
+}
+
+/// Runs the client's down hook when dropped, before the routes and the TUN
+/// device are torn down.
+pub struct Down {
+    script: Option<String>,
+    env: Env,
+}
+
+impl Down {
+    pub fn new(script: Option<String>, env: Env) -> Down {
+        Down {
+            script,
+            env,
+        }
+    }
+}
+
+impl Drop for Down {
+    fn drop(&mut self) {
+        if let Some(ref script) = self.script {
+            if let Err(e) = run(script, "down", &self.env) {
+                warn!("{}", e);
+            }
+        }
+    }
+}
+
+/// The server's connect and disconnect hooks. Remembers what each session was
+/// announced with, so that the disconnect hook sees the same variables.
+pub struct Sessions {
+    pub on_connect: Option<String>,
+    pub on_disconnect: Option<String>,
+    sessions: HashMap<u8, Env>,
+    children: Vec<(Child, &'static str)>,
+}
+
+impl Sessions {
+    pub fn new(on_connect: Option<String>, on_disconnect: Option<String>) -> Sessions {
+        Sessions {
+            on_connect,
+            on_disconnect,
+            sessions: HashMap::new(),
+            children: Vec::new(),
+        }
+    }
+
+    pub fn connected(&mut self, id: u8, env: Env) {
+        if let Some(old) = self.sessions.insert(id, env.clone()) {
+            self.notify_disconnect(&old, "replaced by a new session");
+        }
+        if let Some(ref script) = self.on_connect {
+            spawn(&mut self.children, script, "connect", &env, "");
+        }
+    }
+
+    pub fn disconnected(&mut self, id: u8, reason: &str) {
+        if let Some(env) = self.sessions.remove(&id) {
+            self.notify_disconnect(&env, reason);
+        }
+    }
+
+    fn notify_disconnect(&mut self, env: &Env, reason: &str) {
+        if let Some(ref script) = self.on_disconnect {
+            spawn(&mut self.children, script, "disconnect", env, reason);
+        }
+    }
+
+    /// Collects the hooks that have finished. Called from the event loop, so
+    /// that no thread waits for each of them.
+    pub fn reap(&mut self) {
+        reap(&mut self.children);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hook::*;
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+
+    fn env() -> Env {
+        Env {
+            interface: String::from("tun0"),
+            address: String::from("10.10.10.2"),
+            endpoint: String::from("192.0.2.1:40000"),
+            identity: String::from("laptop"),
+        }
+    }
+
+    #[test]
+    fn run_test() {
+        run("test \"$KYTAN_EVENT $KYTAN_ADDRESS\" = \"up 10.10.10.2\"", "up", &env()).unwrap();
+        assert!(run("exit 1", "up", &env()).is_err());
+    }
+
+    #[test]
+    fn sessions_test() {
+        let path = std::env::temp_dir().join(format!("kytan-hook-{}", std::process::id()));
+        let script = format!(
+            "echo \"$KYTAN_EVENT $KYTAN_IDENTITY $KYTAN_REASON\" >> {}",
+            path.display()
+        );
+        let mut sessions = Sessions::new(None, Some(script));
+        sessions.connected(2, env());
+        sessions.disconnected(2, "expired");
+        sessions.disconnected(2, "expired");
+        for _ in 0..50 {
+            if path.exists() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "disconnect laptop expired\n"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reap_test() {
+        let mut sessions = Sessions::new(Some(String::from("exit 1")), None);
+        sessions.connected(2, env());
+        assert_eq!(sessions.children.len(), 1);
+        for _ in 0..50 {
+            sessions.reap();
+            if sessions.children.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(sessions.children.is_empty());
+        let mut sessions = Sessions::new(Some(String::from("sleep 1")), None);
+        for id in 0..MAX_RUNNING as u8 + 1 {
+            sessions.connected(id, env());
+        }
+        assert_eq!(sessions.children.len(), MAX_RUNNING);
+    }
+}
@@ -0,0 +1,351 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::dns;
+use crate::firewall;
+use crate::utils;
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub const DEFAULT_PATH: &str = "/var/run/kytan.journal";
+
+/// A change to the system that has to be undone when the client exits.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "change", rename_all = "kebab-case")]
+pub enum Change {
+    Route { net: String },
+    HostRoute { host: String },
+    /// The default route was replaced; `origin` is the original gateway.
+    DefaultRoute { origin: String },
+    Rule { args: Vec<String> },
+    /// The default route of a table used for policy routing.
+    Table { table: String },
+    Dns { backend: dns::Backend, if_name: String },
+    KillSwitch,
+}
+
+impl Change {
+    /// Whether the change is gone already, e.g. a route through the TUN
+    /// device of a process that crashed, which went away with the device.
+    fn is_undone(&self) -> bool {
+        match *self {
+            Change::Route { ref net } => !utils::route_exists(&["exact", net]),
+            Change::HostRoute { ref host } => !utils::route_exists(&["exact", host]),
+            Change::Table { ref table } => !utils::route_exists(&["table", table]),
+            Change::Dns {
+                backend: dns::Backend::Resolved,
+                ref if_name,
+            } => cfg!(target_os = "linux") && !Path::new("/sys/class/net").join(if_name).exists(),
+            _ => false,
+        }
+    }
+
+    fn undo(&self) -> Result<(), String> {
+        if self.is_undone() {
+            return Ok(());
+        }
+        match *self {
+            Change::Route { ref net } => utils::delete_route(utils::RouteType::Net, net),
+            Change::HostRoute { ref host } => utils::delete_route(utils::RouteType::Host, host),
+            Change::DefaultRoute { ref origin } => {
+                // The tunnel's default route is gone if the TUN device is.
+                let _ = utils::delete_default_gateway();
+                utils::set_default_gateway(origin)
+            }
+            Change::Rule { ref args } => {
+                let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+                utils::ip_rule("del", &args)
+            }
+            Change::Table { ref table } => utils::ip_route(&["del", "default", "table", table]),
+            Change::Dns {
+                backend,
+                ref if_name,
+            } => dns::revert(backend, if_name),
+            Change::KillSwitch => firewall::remove().map(|_| ()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct State {
+    pid: u32,
+    changes: Vec<Change>,
+}
+
+struct Journal {
+    path: PathBuf,
+    file: File,
+    state: State,
+}
+
+impl Journal {
+    fn save(&mut self) -> Result<(), String> {
+        let json = serde_json::to_string(&self.state).map_err(|e| e.to_string())?;
+        self.file
+            .set_len(0)
+            .and_then(|_| self.file.seek(SeekFrom::Start(0)))
+            .and_then(|_| self.file.write_all(json.as_bytes()))
+            .and_then(|_| self.file.sync_data())
+            .map_err(|e| format!("{}: {}", self.path.display(), e))
+    }
+}
+
+static JOURNAL: Mutex<Option<Journal>> = Mutex::new(None);
+
+/// Writes `change` to the journal, if one is open.
+pub fn record(change: Change) {
+    if let Some(ref mut journal) = *JOURNAL.lock().unwrap() {
+        journal.state.changes.push(change);
+        if let Err(e) = journal.save() {
+            warn!("Unable to write journal: {}", e);
+        }
+    }
+}
+
+/// Removes `change` from the journal once it has been undone.
+pub fn forget(change: &Change) {
+    if let Some(ref mut journal) = *JOURNAL.lock().unwrap() {
+        if let Some(i) = journal.state.changes.iter().rposition(|c| c == change) {
+            journal.state.changes.remove(i);
+            if let Err(e) = journal.save() {
+                warn!("Unable to write journal: {}", e);
+            }
+        }
+    }
+}
+
+/// Changes that are journaled as they are made and undone, in reverse order,
+/// when dropped.
+#[derive(Default)]
+pub struct Changes {
+    changes: Vec<Change>,
+}
+
+impl Changes {
+    pub fn push(&mut self, change: Change) {
+        record(change.clone());
+        self.changes.push(change);
+    }
+}
+
+impl Drop for Changes {
+    fn drop(&mut self) {
+        while let Some(change) = self.changes.pop() {
+            match change.undo() {
+                Ok(()) => forget(&change),
+                Err(e) => warn!("Unable to undo {:?}: {}. Run `kytan cleanup`.", change, e),
+            }
+        }
+    }
+}
+
+/// Locks the journal for this process. The lock goes away with the process,
+/// however it exits, so a locked journal belongs to a running kytan.
+fn lock(file: &File) -> bool {
+    unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) == 0 }
+}
+
+fn read_state(file: &mut File, path: &Path) -> Result<State, String> {
+    let mut json = String::new();
+    file.read_to_string(&mut json)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    if json.is_empty() {
+        return Ok(State::default());
+    }
+    serde_json::from_str(&json).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Undoes `changes` in reverse order. Returns those that could not be undone.
+fn undo_all<F>(changes: &[Change], undo: F) -> Vec<Change>
+where
+    F: Fn(&Change) -> Result<(), String>,
+{
+    let mut failed = Vec::new();
+    for change in changes.iter().rev() {
+        info!("Undoing {:?}.", change);
+        if let Err(e) = undo(change) {
+            warn!("Unable to undo {:?}: {}", change, e);
+            failed.insert(0, change.clone());
+        }
+    }
+    failed
+}
+
+/// Undoes the changes left in the journal at `path` by a kytan process that
+/// did not exit cleanly, or with `force` by one that is still running. Those
+/// that cannot be undone stay in the journal and make it an error. Returns
+/// how many changes were undone.
+pub fn recover(path: &Path, force: bool) -> Result<usize, String> {
+    recover_with(path, force, Change::undo)
+}
+
+fn recover_with<F>(path: &Path, force: bool, undo: F) -> Result<usize, String>
+where
+    F: Fn(&Change) -> Result<(), String>,
+{
+    let mut file = match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(format!("{}: {}", path.display(), e)),
+    };
+    let state = read_state(&mut file, path)?;
+    if !lock(&file) {
+        if !force {
+            return Err(format!("kytan is still running as process {}", state.pid));
+        }
+        warn!("Undoing the changes of process {}, which is still running.", state.pid);
+    }
+    let failed = undo_all(&state.changes, undo);
+    if failed.is_empty() {
+        fs::remove_file(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        return Ok(state.changes.len());
+    }
+    let mut left = Journal {
+        path: path.to_path_buf(),
+        file,
+        state: State {
+            pid: 0,
+            changes: failed,
+        },
+    };
+    left.save()?;
+    Err(format!(
+        "{} of {} changes could not be undone and are kept in {}",
+        left.state.changes.len(),
+        state.changes.len(),
+        path.display()
+    ))
+}
+
+/// Closes the journal when dropped, removing it if every change was undone.
+pub struct Guard;
+
+/// Opens the journal at `path`, first undoing what a previous run left behind;
+/// what cannot be undone is kept. The file stays open and locked so that it
+/// can still be written once privileges are dropped.
+pub fn open(path: &Path) -> Result<Guard, String> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    if !lock(&file) {
+        return Err(format!("{}: kytan is still running", path.display()));
+    }
+    let state = read_state(&mut file, path)?;
+    let left = undo_all(&state.changes, Change::undo);
+    match state.changes.len() - left.len() {
+        0 => {}
+        n => warn!("Undid {} changes left behind by a previous run.", n),
+    }
+    if !left.is_empty() {
+        warn!(
+            "{} changes left behind by a previous run could not be undone. Run `kytan cleanup`.",
+            left.len()
+        );
+    }
+    let mut journal = Journal {
+        path: path.to_path_buf(),
+        file,
+        state: State {
+            pid: std::process::id(),
+            changes: left,
+        },
+    };
+    journal.save()?;
+    *JOURNAL.lock().unwrap() = Some(journal);
+    Ok(Guard)
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Some(journal) = JOURNAL.lock().unwrap().take() {
+            if !journal.state.changes.is_empty() {
+                warn!(
+                    "{} changes were not undone. Run `kytan cleanup`.",
+                    journal.state.changes.len()
+                );
+                return;
+            }
+            // Privileges may have been dropped, in which case an empty
+            // journal is left behind instead.
+            if fs::remove_file(&journal.path).is_err() {
+                let _ = journal.file.set_len(0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::journal::*;
+
+    #[test]
+    fn recover_test() {
+        let path = std::env::temp_dir().join(format!("kytan-journal-{}", std::process::id()));
+        let undo = |change: &Change| match *change {
+            Change::KillSwitch => Err(String::from("iptables: not found")),
+            _ => Ok(()),
+        };
+        assert_eq!(recover_with(&path, false, undo).unwrap(), 0);
+
+        // A journal left by a process that is gone is recovered.
+        fs::write(&path, "").unwrap();
+        assert_eq!(recover_with(&path, false, undo).unwrap(), 0);
+        assert!(!path.exists());
+
+        let route = Change::Route {
+            net: String::from("198.51.100.0/24"),
+        };
+        let state = State {
+            pid: 0,
+            changes: vec![route.clone()],
+        };
+        fs::write(&path, serde_json::to_string(&state).unwrap()).unwrap();
+        assert_eq!(recover_with(&path, false, undo).unwrap(), 1);
+        assert!(!path.exists());
+
+        // Changes that cannot be undone are kept.
+        let state = State {
+            pid: 0,
+            changes: vec![Change::KillSwitch, route.clone()],
+        };
+        fs::write(&path, serde_json::to_string(&state).unwrap()).unwrap();
+        assert!(recover_with(&path, false, undo).is_err());
+        let left: State = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(left.changes, vec![Change::KillSwitch]);
+
+        // One locked by a running process is left alone, unless forced.
+        let state = State {
+            pid: 1,
+            changes: vec![route],
+        };
+        fs::write(&path, serde_json::to_string(&state).unwrap()).unwrap();
+        let running = File::open(&path).unwrap();
+        assert!(lock(&running));
+        let never = |_: &Change| -> Result<(), String> { unreachable!() };
+        assert!(recover_with(&path, false, never).unwrap_err().contains("still running"));
+        assert_eq!(recover_with(&path, true, undo).unwrap(), 1);
+        assert!(!path.exists());
+    }
+}
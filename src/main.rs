@@ -17,34 +17,126 @@ mod utils;
 mod network;
 mod packet;
 mod cli;
+mod acl;
+mod config;
+mod conn;
+mod control;
+mod dbus;
+mod dns;
+mod firewall;
+mod hook;
+mod journal;
+mod metrics;
+mod pmtu;
+mod privilege;
+mod quota;
+mod ratelimit;
+mod switch;
 
 
+use std::path::Path;
+use std::process;
 use std::sync::atomic::Ordering;
 use env_logger;
 use libc;
 
 
-extern "C" fn handle_signal(_: libc::c_int) {
+extern "C" fn handle_signal(signal: libc::c_int) {
     network::INTERRUPTED.store(true, Ordering::Relaxed);
+    let child = privilege::CHILD.load(Ordering::Relaxed);
+    if child > 0 {
+        unsafe {
+            libc::kill(child, signal);
+        }
+    }
+}
+
+extern "C" fn handle_reload(_: libc::c_int) {
+    network::RELOAD.store(true, Ordering::Relaxed);
 }
 
 fn main() {
     env_logger::init();
 
-    if !utils::is_root() {
-        panic!("Please run as root");
+    let args = match cli::get_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    if let cli::Args::Status(ref status) = args {
+        if let Err(e) = control::status(&status.control, status.json) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+    if let cli::Args::Usage(ref usage) = args {
+        if let Err(e) = control::usage(&usage.control, usage.json) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+    if let cli::Args::Command(ref command) = args {
+        if let Err(e) = control::command(&command.control, &command.request) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+    if let cli::Args::Unblock = args {
+        match firewall::remove() {
+            Ok(true) => println!("Kill switch removed."),
+            Ok(false) => println!("No kill switch found."),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+    if let cli::Args::Cleanup { force } = args {
+        match journal::recover(Path::new(journal::DEFAULT_PATH), force) {
+            Ok(0) => println!("Nothing to clean up."),
+            Ok(n) => println!("Undid {} changes.", n),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        return;
     }
 
+    let mut caps = vec![privilege::Capability::NetAdmin];
+    if let cli::Args::Server(ref server) = args {
+        if server.port < 1024 {
+            caps.push(privilege::Capability::NetBindService);
+        }
+    }
+    if let Err(e) = privilege::acquire(&caps) {
+        panic!("{}", e);
+    }
 
     unsafe {
         libc::signal(libc::SIGINT, handle_signal as libc::sighandler_t);
         libc::signal(libc::SIGTERM, handle_signal as libc::sighandler_t);
+        libc::signal(libc::SIGHUP, handle_reload as *const () as libc::sighandler_t);
     }
 
-    match cli::get_args().unwrap() {
-        cli::Args::Client(client) => network::connect(&client.remote_addr, client.port, client.default_route, &client.key),
-        cli::Args::Server(server) => network::serve(server.port, &server.key, server.dns),
+    match args {
+        cli::Args::Client(client) => network::connect(client),
+        cli::Args::Server(server) => network::serve(server),
+        cli::Args::Status(_)
+        | cli::Args::Usage(_)
+        | cli::Args::Command(_)
+        | cli::Args::Unblock
+        | cli::Args::Cleanup { .. } => unreachable!(),
     }
 
-    println!("SIGINT/SIGTERM captured. Exit.");
+    if network::INTERRUPTED.load(Ordering::Relaxed) {
+        println!("SIGINT/SIGTERM captured. Exit.");
+    }
 }
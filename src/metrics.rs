@@ -0,0 +1,278 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::conn;
+use log::{info, warn};
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+/// Counters updated by the `serve` and `connect` loops. "In" is traffic
+/// received from the tunnel peer, "out" is traffic sent to it.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub handshakes_accepted: u64,
+    pub handshakes_rejected: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub packets_in: u64,
+    pub packets_out: u64,
+    pub decrypt_failures: u64,
+    pub decompress_failures: u64,
+    pub tun_write_errors: u64,
+    pub rate_limited_in: u64,
+    pub rate_limited_out: u64,
+    pub client_to_client_denied: u64,
+    pub spoofed_in: u64,
+}
+
+fn metric(text: &mut String, name: &str, kind: &str, help: &str, samples: &[(&str, u64)]) {
+    text.push_str(&format!("# HELP kytan_{} {}\n", name, help));
+    text.push_str(&format!("# TYPE kytan_{} {}\n", name, kind));
+    for &(labels, value) in samples {
+        text.push_str(&format!("kytan_{}{} {}\n", name, labels, value));
+    }
+}
+
+impl Metrics {
+    /// Renders the Prometheus text format. `gauges` are (name, help, value)
+    /// computed by the caller at scrape time.
+    pub fn render(&self, gauges: &[(&str, &str, u64)]) -> String {
+        let mut text = String::new();
+        for &(name, help, value) in gauges {
+            metric(&mut text, name, "gauge", help, &[("", value)]);
+        }
+        metric(
+            &mut text,
+            "handshakes_total",
+            "counter",
+            "Handshakes by result.",
+            &[
+                ("{result=\"accepted\"}", self.handshakes_accepted),
+                ("{result=\"rejected\"}", self.handshakes_rejected),
+            ],
+        );
+        metric(
+            &mut text,
+            "bytes_total",
+            "counter",
+            "Encrypted bytes exchanged with tunnel peers.",
+            &[
+                ("{direction=\"in\"}", self.bytes_in),
+                ("{direction=\"out\"}", self.bytes_out),
+            ],
+        );
+        metric(
+            &mut text,
+            "packets_total",
+            "counter",
+            "Data packets exchanged with tunnel peers.",
+            &[
+                ("{direction=\"in\"}", self.packets_in),
+                ("{direction=\"out\"}", self.packets_out),
+            ],
+        );
+        metric(
+            &mut text,
+            "decrypt_failures_total",
+            "counter",
+            "Datagrams that failed to decrypt or decode.",
+            &[("", self.decrypt_failures)],
+        );
+        metric(
+            &mut text,
+            "decompress_failures_total",
+            "counter",
+            "Data packets that failed to decompress.",
+            &[("", self.decompress_failures)],
+        );
+        metric(
+            &mut text,
+            "tun_write_errors_total",
+            "counter",
+            "Packets that could not be written to the TUN device.",
+            &[("", self.tun_write_errors)],
+        );
+        metric(
+            &mut text,
+            "rate_limited_packets_total",
+            "counter",
+            "Data packets dropped by rate limits.",
+            &[
+                ("{direction=\"in\"}", self.rate_limited_in),
+                ("{direction=\"out\"}", self.rate_limited_out),
+            ],
+        );
+        metric(
+            &mut text,
+            "client_to_client_denied_total",
+            "counter",
+            "Packets between clients dropped by the forwarding policy.",
+            &[("", self.client_to_client_denied)],
+        );
+        metric(
+            &mut text,
+            "spoofed_packets_total",
+            "counter",
+            "Packets from clients dropped for a source address that is not theirs.",
+            &[("", self.spoofed_in)],
+        );
+        text
+    }
+}
+
+/// A minimal HTTP listener answering every `GET /metrics`.
+pub struct Listener {
+    listener: TcpListener,
+    conns: conn::Connections<TcpStream>,
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Listener {
+    /// Connections get the tokens from `first` on.
+    pub fn bind(addr: SocketAddr, first: mio::Token) -> Result<Listener, String> {
+        let listener = TcpListener::bind(addr).map_err(|e| format!("{}: {}", addr, e))?;
+        listener
+            .set_nonblocking(true)
+            .map_err(|e| format!("{}: {}", addr, e))?;
+        info!("Serving metrics on http://{}/metrics.", addr);
+        Ok(Listener {
+            listener,
+            conns: conn::Connections::new(first),
+        })
+    }
+
+    /// Registers every pending connection with `registry`.
+    pub fn accept(&mut self, registry: &mio::Registry) {
+        loop {
+            let result = self
+                .listener
+                .accept()
+                .and_then(|(stream, _)| stream.set_nonblocking(true).map(|_| stream));
+            let stream = match result {
+                Ok(stream) => stream,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("Metrics listener: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = self.conns.add(registry, stream) {
+                warn!("Metrics listener: {}", e);
+            }
+        }
+    }
+
+    pub fn owns(&self, token: mio::Token) -> bool {
+        self.conns.owns(token)
+    }
+
+    /// Answers the connection of `token` with the text returned by `render`
+    /// once its request line is in.
+    pub fn ready<F>(&mut self, registry: &mio::Registry, token: mio::Token, render: F)
+    where
+        F: FnOnce() -> String,
+    {
+        self.conns.ready(registry, token, |request_line| {
+            response(request_line, &render()).into_bytes()
+        });
+    }
+}
+
+fn response(request: &str, body: &str) -> String {
+    let (status, body) = match request.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/metrics"] => ("200 OK", body),
+        ["GET", _] => ("404 Not Found", "Not Found\n"),
+        _ => ("405 Method Not Allowed", "Method Not Allowed\n"),
+    };
+    format!(
+        "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::*;
+    use std::io::{Read, Write};
+    use std::thread;
+
+    #[test]
+    fn render_test() {
+        let metrics = Metrics {
+            handshakes_accepted: 2,
+            bytes_in: 100,
+            ..Default::default()
+        };
+        let text = metrics.render(&[("sessions", "Active sessions.", 1)]);
+        assert!(text.starts_with("# HELP kytan_sessions Active sessions.\n"));
+        assert!(text.contains("# TYPE kytan_sessions gauge\nkytan_sessions 1\n"));
+        assert!(text.contains("kytan_handshakes_total{result=\"accepted\"} 2\n"));
+        assert!(text.contains("kytan_bytes_total{direction=\"in\"} 100\n"));
+        assert!(text.contains("kytan_tun_write_errors_total 0\n"));
+    }
+
+    #[test]
+    fn response_test() {
+        assert!(response("GET /metrics HTTP/1.1", "x\n").starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response("GET /metrics HTTP/1.1", "x\n").ends_with("Content-Length: 2\r\n\r\nx\n"));
+        assert!(response("GET / HTTP/1.1", "x\n").starts_with("HTTP/1.0 404"));
+        assert!(response("POST /metrics HTTP/1.1", "x\n").starts_with("HTTP/1.0 405"));
+    }
+
+    #[test]
+    fn listener_test() {
+        let mut poll = mio::Poll::new().unwrap();
+        let mut listener =
+            Listener::bind("127.0.0.1:0".parse().unwrap(), mio::Token(1)).unwrap();
+        let addr = listener.listener.local_addr().unwrap();
+        poll.registry()
+            .register(
+                &mut mio::unix::SourceFd(&listener.as_raw_fd()),
+                mio::Token(0),
+                mio::Interest::READABLE,
+            )
+            .unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+        let mut events = mio::Events::with_capacity(8);
+        let mut served = false;
+        while !served {
+            poll.poll(&mut events, None).unwrap();
+            for event in events.iter() {
+                match event.token() {
+                    mio::Token(0) => listener.accept(poll.registry()),
+                    token => listener.ready(poll.registry(), token, || {
+                        served = true;
+                        String::from("kytan_sessions 0\n")
+                    }),
+                }
+            }
+        }
+        let response = client.join().unwrap();
+        assert!(response.ends_with("\r\n\r\nkytan_sessions 0\n"));
+    }
+}
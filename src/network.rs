@@ -12,7 +12,21 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::acl;
+use crate::cli;
+use crate::config;
+use crate::control;
 use crate::device;
+use crate::dns;
+use crate::firewall;
+use crate::hook;
+use crate::journal;
+use crate::metrics;
+use crate::pmtu;
+use crate::privilege;
+use crate::quota;
+use crate::ratelimit;
+use crate::switch;
 use crate::utils;
 use bincode::{deserialize, serialize};
 use dns_lookup;
@@ -22,23 +36,32 @@ use rand::{thread_rng, Rng};
 use ring::{aead, pbkdf2};
 use serde_derive::{Deserialize, Serialize};
 use snap;
-use std::io::{Read, Write};
-use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
 use std::num::NonZeroU32;
 use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
 use transient_hashmap::TransientHashMap;
+use zeroize::{Zeroize, Zeroizing};
 
 pub static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+pub static RELOAD: AtomicBool = AtomicBool::new(false);
 static CONNECTED: AtomicBool = AtomicBool::new(false);
 static LISTENING: AtomicBool = AtomicBool::new(false);
 const KEY_LEN: usize = 32;
+/// What a data message adds to a packet of up to `device::MAX_MTU` bytes,
+/// even one that does not compress, not counting the IP and UDP headers.
+const DATA_OVERHEAD: u32 = 64;
 
 type Id = u8;
 type Token = u64;
 
-fn generate_add_nonce(_secret: &str) -> (aead::Aad<[u8; 0]>, aead::Nonce) {
+fn generate_add_nonce() -> (aead::Aad<[u8; 0]>, aead::Nonce) {
     let nonce = aead::Nonce::assume_unique_for_key([0; 12]);
     let aad = aead::Aad::empty();
     (aad, nonce)
@@ -46,33 +69,694 @@ fn generate_add_nonce(_secret: &str) -> (aead::Aad<[u8; 0]>, aead::Nonce) {
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 enum Message {
-    Request,
-    Response { id: Id, token: Token, dns: String },
+    Request {
+        name: String,
+        subnets: Vec<utils::Ipv4Net>,
+        tap: bool,
+        mtu: u32,
+    },
+    Response {
+        id: Id,
+        token: Token,
+        dns: dns::Settings,
+        routing: utils::Routing,
+        /// The smaller of the two ends' MTUs.
+        mtu: u32,
+    },
     Data { id: Id, token: Token, data: Vec<u8> },
+    Disconnect { token: Token, reason: String },
+    /// Padded to the size of a data message to discover the path MTU.
+    Probe {
+        id: Id,
+        token: Token,
+        seq: u32,
+        padding: Vec<u8>,
+    },
+    ProbeAck { token: Token, seq: u32 },
+    /// The MTU found by path MTU discovery, for the server to adopt.
+    Mtu { id: Id, token: Token, mtu: u32 },
+}
+
+fn seal(key: &aead::LessSafeKey, msg: &Message) -> Vec<u8> {
+    let mut sealed = serialize(msg).unwrap();
+    let (aad, nonce) = generate_add_nonce();
+    key.seal_in_place_append_tag(nonce, aad, &mut sealed).unwrap();
+    sealed
+}
+
+/// The size of the datagram that carries a packet of `mtu` bytes.
+fn datagram_len(mtu: u32, tap: bool) -> usize {
+    let header = if tap { 14 } else { 0 };
+    (mtu + header + DATA_OVERHEAD) as usize
+}
+
+/// A probe that is `len` bytes long once sealed.
+fn seal_probe(key: &aead::LessSafeKey, id: Id, token: Token, seq: u32, len: usize) -> Vec<u8> {
+    let mut msg = Message::Probe {
+        id,
+        token,
+        seq,
+        padding: Vec::new(),
+    };
+    let empty_len = seal(key, &msg).len();
+    if let Message::Probe {
+        ref mut padding, ..
+    } = msg
+    {
+        padding.resize(len.saturating_sub(empty_len), 0);
+    }
+    seal(key, &msg)
+}
+
+/// Sends a probe with the Don't Fragment bit. One that is too big for the
+/// local link fails right away, which counts as lost like any other.
+fn send_probe(sockfd: &mio::net::UdpSocket, addr: SocketAddr, probe: &[u8]) {
+    let fd = sockfd.as_raw_fd();
+    let result = utils::set_probing(fd, true)
+        .and_then(|_| sockfd.send_to(probe, addr).map_err(|e| e.to_string()));
+    if let Err(e) = result {
+        info!("Probe of {} bytes not sent: {}", probe.len(), e);
+    }
+    if let Err(e) = utils::set_probing(fd, false) {
+        warn!("Unable to reset path MTU discovery: {}", e);
+    }
+}
+
+/// Decrypts and decodes a datagram, counting the ones that fail.
+fn open(
+    key: &aead::LessSafeKey,
+    buf: &mut [u8],
+    metrics: &mut metrics::Metrics,
+) -> Option<Message> {
+    let (aad, nonce) = generate_add_nonce();
+    let msg = match key.open_in_place(nonce, aad, buf) {
+        Ok(decrypted_buf) => deserialize(decrypted_buf).map_err(|e| e.to_string()),
+        Err(_) => Err(String::from("decryption failed")),
+    };
+    match msg {
+        Ok(msg) => Some(msg),
+        Err(e) => {
+            metrics.decrypt_failures += 1;
+            warn!("Dropped datagram: {}", e);
+            None
+        }
+    }
+}
+
+/// Decompresses a data packet, counting the ones that fail.
+fn decompress(
+    decoder: &mut snap::raw::Decoder,
+    data: &[u8],
+    metrics: &mut metrics::Metrics,
+) -> Option<Vec<u8>> {
+    match decoder.decompress_vec(data) {
+        Ok(decompressed_data) => Some(decompressed_data),
+        Err(e) => {
+            metrics.decompress_failures += 1;
+            warn!("Dropped packet: {}", e);
+            None
+        }
+    }
+}
+
+fn write_tun(tun: &mut device::Tun, packet: &[u8], metrics: &mut metrics::Metrics) {
+    if let Err(e) = tun.write_all(packet) {
+        metrics.tun_write_errors += 1;
+        warn!("Unable to write to TUN device: {}", e);
+    }
+}
+
+/// Decompresses a data packet and writes it to the TUN device.
+fn deliver(
+    tun: &mut device::Tun,
+    decoder: &mut snap::raw::Decoder,
+    data: &[u8],
+    metrics: &mut metrics::Metrics,
+) {
+    if let Some(packet) = decompress(decoder, data, metrics) {
+        write_tun(tun, &packet, metrics);
+    }
+}
+
+/// Compresses, encrypts and sends a packet to the client of `session`.
+fn send_data(
+    sockfd: &mio::net::UdpSocket,
+    key: &aead::LessSafeKey,
+    encoder: &mut snap::raw::Encoder,
+    session: &mut Session,
+    id: Id,
+    packet: &[u8],
+    metrics: &mut metrics::Metrics,
+) {
+    let msg = Message::Data {
+        id,
+        token: session.token,
+        data: encoder.compress_vec(packet).unwrap(),
+    };
+    let encoded_msg = serialize(&msg).unwrap();
+    let mut encrypted_msg = encoded_msg.clone();
+    encrypted_msg.resize(encoded_msg.len() + key.algorithm().tag_len(), 0);
+    let (aad, nonce) = generate_add_nonce();
+    key.seal_in_place_append_tag(nonce, aad, &mut encrypted_msg)
+        .unwrap();
+    let mut sent_len = 0;
+    while sent_len < encrypted_msg.len() {
+        sent_len += sockfd
+            .send_to(&encrypted_msg[sent_len..encrypted_msg.len()], session.addr)
+            .unwrap();
+    }
+    session.bytes_out += sent_len as u64;
+    metrics.bytes_out += sent_len as u64;
+    metrics.packets_out += 1;
+}
+
+struct Session {
+    token: Token,
+    addr: SocketAddr,
+    name: String,
+    /// Whose quota the traffic counts against, see `identity`.
+    identity: String,
+    quota: quota::Quota,
+    rate: ratelimit::Limits,
+    upload: Option<ratelimit::TokenBucket>,
+    download: Option<ratelimit::TokenBucket>,
+    bytes_in: u64,
+    bytes_out: u64,
+    dropped_in: u64,
+    dropped_out: u64,
+    last_handshake: SystemTime,
+    last_packet: Option<SystemTime>,
+    /// Networks behind the client, routed into the TUN device while the
+    /// session lasts.
+    subnets: Vec<utils::Ipv4Net>,
+    routes: Option<utils::Routes>,
+    /// Addresses learned from the client's frames in TAP mode.
+    macs: switch::Macs,
+    mtu: u32,
+    mtu_route: Option<utils::MtuRoute>,
+}
+
+impl Session {
+    fn new(
+        token: Token,
+        addr: SocketAddr,
+        name: String,
+        identity: String,
+        quota: quota::Quota,
+        rate: ratelimit::Limits,
+    ) -> Session {
+        let mut session = Session {
+            token,
+            addr,
+            name,
+            identity,
+            quota,
+            rate: Default::default(),
+            upload: None,
+            download: None,
+            bytes_in: 0,
+            bytes_out: 0,
+            dropped_in: 0,
+            dropped_out: 0,
+            last_handshake: SystemTime::now(),
+            last_packet: None,
+            subnets: Vec::new(),
+            routes: None,
+            macs: Default::default(),
+            mtu: device::DEFAULT_MTU,
+            mtu_route: None,
+        };
+        session.set_rate(rate);
+        session
+    }
+
+    fn set_rate(&mut self, rate: ratelimit::Limits) {
+        let now = Instant::now();
+        self.rate = rate;
+        self.upload = rate.up.map(|up| ratelimit::TokenBucket::new(up, now));
+        self.download = rate.down.map(|down| ratelimit::TokenBucket::new(down, now));
+    }
+
+    fn route_subnets(&mut self, id: Id, subnets: Vec<utils::Ipv4Net>) -> Result<(), String> {
+        self.routes = Some(utils::Routes::create(
+            &format!("10.10.10.{}", id),
+            &subnets,
+        )?);
+        self.subnets = subnets;
+        Ok(())
+    }
+
+    /// Whether an IP packet from the client comes from its tunnel address,
+    /// `id`, or one of its subnets. Anything else is spoofed.
+    fn sends_from(&self, id: Id, packet: &[u8]) -> bool {
+        if packet.len() < 20 || packet[0] >> 4 != 4 {
+            return false;
+        }
+        let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+        src == Ipv4Addr::new(10, 10, 10, id) || self.subnets.iter().any(|net| net.contains(src))
+    }
+
+    /// Limits the packets sent to the client to `mtu` bytes with a route, if
+    /// that is less than the MTU of the TUN device and routes can be added.
+    fn set_mtu(
+        &mut self,
+        if_name: &str,
+        id: Id,
+        mtu: u32,
+        device_mtu: u32,
+        route: bool,
+    ) -> Result<(), String> {
+        self.mtu_route = None;
+        if route && mtu < device_mtu {
+            let host = format!("10.10.10.{}", id);
+            self.mtu_route = Some(utils::MtuRoute::create(if_name, &host, mtu)?);
+        }
+        self.mtu = mtu;
+        Ok(())
+    }
+
+    /// Checks a packet of `len` bytes for the client against its rate limit
+    /// and quota.
+    fn admit_out(
+        &mut self,
+        len: u64,
+        accounting: &mut quota::Accounting,
+        metrics: &mut metrics::Metrics,
+    ) -> quota::Verdict {
+        if let Some(ref mut download) = self.download {
+            if !download.take(len, Instant::now()) {
+                self.dropped_out += 1;
+                metrics.rate_limited_out += 1;
+                return quota::Verdict::Drop;
+            }
+        }
+        accounting.record(
+            &self.identity,
+            &self.quota,
+            len,
+            false,
+            control::unix_time(SystemTime::now()),
+        )
+    }
+
+    /// Whether `target` names this session by id, address, endpoint or name.
+    fn matches(&self, id: Id, target: &str) -> bool {
+        target == id.to_string()
+            || target == format!("10.10.10.{}", id)
+            || target == self.addr.to_string()
+            || target == self.addr.ip().to_string()
+            || (!self.name.is_empty() && target == self.name)
+    }
+
+    fn info(&self, id: Id) -> control::SessionInfo {
+        control::SessionInfo {
+            id,
+            address: format!("10.10.10.{}", id),
+            endpoint: self.addr.to_string(),
+            name: self.name.clone(),
+            mtu: self.mtu,
+            bytes_in: self.bytes_in,
+            bytes_out: self.bytes_out,
+            dropped_in: self.dropped_in,
+            dropped_out: self.dropped_out,
+            last_handshake: control::unix_time(self.last_handshake),
+            last_packet: self.last_packet.map(control::unix_time),
+        }
+    }
+}
+
+/// The peer name of a client, or its endpoint address if no peers are
+/// configured: without them, anyone could claim a name.
+fn identity(peer: Option<&config::Peer>, addr: SocketAddr) -> String {
+    match peer {
+        Some(peer) => peer.name.clone(),
+        None => addr.ip().to_string(),
+    }
+}
+
+/// The server's quota with the overrides of `peer`, if any.
+fn peer_quota(quota: quota::Quota, peer: Option<&config::Peer>) -> quota::Quota {
+    match peer {
+        Some(peer) => quota::Quota {
+            daily: peer.quota_daily.map(|bytes| bytes.0).or(quota.daily),
+            monthly: peer.quota_monthly.map(|bytes| bytes.0).or(quota.monthly),
+            action: quota.action,
+        },
+        None => quota,
+    }
+}
+
+/// The server's rate limits with the overrides of `peer`, if any.
+fn peer_rate(rate: ratelimit::Limits, peer: Option<&config::Peer>) -> ratelimit::Limits {
+    match peer {
+        Some(peer) => ratelimit::Limits {
+            up: peer.rate_up.map(|bytes| bytes.0).or(rate.up),
+            down: peer.rate_down.map(|bytes| bytes.0).or(rate.down),
+        },
+        None => rate,
+    }
+}
+
+/// Removes a session and tells the client why.
+fn close_session(
+    client_info: &mut TransientHashMap<Id, Session>,
+    leases: &mut Leases,
+    hooks: &mut hook::Sessions,
+    sockfd: &mio::net::UdpSocket,
+    key: &aead::LessSafeKey,
+    id: Id,
+    reason: &str,
+) -> Option<Session> {
+    let session = client_info.remove(&id)?;
+    leases.release(id);
+    hooks.disconnected(id, reason);
+    info!("Closing session of 10.10.10.{}: {}.", id, reason);
+    let msg = Message::Disconnect {
+        token: session.token,
+        reason: String::from(reason),
+    };
+    if let Err(e) = sockfd.send_to(&seal(key, &msg), session.addr) {
+        warn!("Unable to notify {}: {}", session.addr, e);
+    }
+    Some(session)
+}
+
+/// Answers a request that is refused, so that the client does not wait for a
+/// response.
+fn reject(
+    sockfd: &mio::net::UdpSocket,
+    key: &aead::LessSafeKey,
+    addr: SocketAddr,
+    reason: String,
+) {
+    warn!("Rejected request from {}: {}.", addr, reason);
+    let msg = Message::Disconnect {
+        token: 0,
+        reason,
+    };
+    if let Err(e) = sockfd.send_to(&seal(key, &msg), addr) {
+        warn!("Unable to notify {}: {}", addr, e);
+    }
+}
+
+/// Tunnel addresses handed out by the server. Addresses of configured peers
+/// are reserved for them and never handed out to anyone else.
+struct Leases {
+    reserved: Vec<Id>,
+    available: Vec<Id>,
+}
+
+impl Leases {
+    fn new(peers: &[config::Peer]) -> Leases {
+        let mut leases = Leases {
+            reserved: Vec::new(),
+            available: Vec::new(),
+        };
+        leases.update(peers, &[]);
+        leases
+    }
+
+    fn update(&mut self, peers: &[config::Peer], in_use: &[Id]) {
+        self.reserved = peers
+            .iter()
+            .filter_map(|peer| peer.address)
+            .map(|address| address.octets()[3])
+            .collect();
+        let reserved = &self.reserved;
+        self.available = (2..254)
+            .filter(|id| !reserved.contains(id) && !in_use.contains(id))
+            .collect();
+    }
+
+    fn acquire(&mut self, peer: Option<&config::Peer>) -> Option<Id> {
+        match peer.and_then(|peer| peer.address) {
+            Some(address) => Some(address.octets()[3]),
+            None => self.available.pop(),
+        }
+    }
+
+    fn release(&mut self, id: Id) {
+        if !self.reserved.contains(&id) && !self.available.contains(&id) {
+            self.available.push(id);
+        }
+    }
+}
+
+/// Names and endpoint addresses refused by `kytan ban`, kept until
+/// `kytan unban` or a restart.
+#[derive(Default)]
+struct Bans {
+    names: Vec<String>,
+    addrs: Vec<IpAddr>,
+}
+
+impl Bans {
+    fn add(&mut self, target: &str) {
+        match target.parse::<IpAddr>() {
+            Ok(addr) if !self.addrs.contains(&addr) => self.addrs.push(addr),
+            Ok(_) => {}
+            Err(_) if !self.names.iter().any(|name| name == target) => {
+                self.names.push(String::from(target))
+            }
+            Err(_) => {}
+        }
+    }
+
+    fn remove(&mut self, target: &str) -> bool {
+        let (names, addrs) = (self.names.len(), self.addrs.len());
+        self.names.retain(|name| name != target);
+        self.addrs.retain(|addr| addr.to_string() != target);
+        names != self.names.len() || addrs != self.addrs.len()
+    }
+
+    fn contains(&self, name: &str, addr: IpAddr) -> bool {
+        (!name.is_empty() && self.names.iter().any(|n| n == name)) || self.addrs.contains(&addr)
+    }
+}
+
+/// Sessions whose address no longer fits the peers: their peer's static
+/// address changed, or the address is now reserved for another peer.
+fn misplaced_sessions(
+    client_info: &TransientHashMap<Id, Session>,
+    peers: &[config::Peer],
+) -> Vec<Id> {
+    let reserved = |peer: &config::Peer| peer.address.map(|address| address.octets()[3]);
+    client_info
+        .iter()
+        .filter(|&(&id, session)| {
+            match peers.iter().find(|peer| peer.name == session.name) {
+                Some(peer) if reserved(peer).is_some() => reserved(peer) != Some(id),
+                _ => peers.iter().any(|peer| reserved(peer) == Some(id)),
+            }
+        })
+        .map(|(&id, _)| id)
+        .collect()
+}
+
+fn is_authorized(peers: &[config::Peer], name: &str) -> bool {
+    peers.is_empty() || peers.iter().any(|peer| peer.name == name)
+}
+
+/// Checks that every subnet a client announces is allowed for its peer.
+fn check_subnets(peer: Option<&config::Peer>, subnets: &[utils::Ipv4Net]) -> Result<(), String> {
+    let allowed = peer.and_then(|peer| peer.subnets.as_deref()).unwrap_or(&[]);
+    for subnet in subnets {
+        if !allowed.iter().any(|net| net.includes(subnet)) {
+            return Err(format!("subnet {} not allowed", subnet));
+        }
+    }
+    Ok(())
+}
+
+/// Sessions whose subnets overlap with `subnets`. They are replaced if they
+/// belong to the same client reconnecting; anyone else is refused.
+fn replaced_sessions(
+    client_info: &TransientHashMap<Id, Session>,
+    subnets: &[utils::Ipv4Net],
+    identity: &str,
+) -> Result<Vec<Id>, String> {
+    let mut replaced = Vec::new();
+    for (&id, session) in client_info.iter() {
+        if !session
+            .subnets
+            .iter()
+            .any(|net| subnets.iter().any(|subnet| subnet.overlaps(net)))
+        {
+            continue;
+        }
+        if session.identity != identity {
+            return Err(format!("subnets already routed to 10.10.10.{}", id));
+        }
+        replaced.push(id);
+    }
+    Ok(replaced)
+}
+
+/// The session a packet from the TUN device is for: the client with the
+/// destination address, or the one announcing the most specific subnet.
+fn lookup(client_info: &TransientHashMap<Id, Session>, data: &[u8]) -> Option<Id> {
+    if data.len() < 20 {
+        return None;
+    }
+    if data[16..19] == [10, 10, 10] {
+        return Some(data[19]);
+    }
+    let dst = Ipv4Addr::new(data[16], data[17], data[18], data[19]);
+    client_info
+        .iter()
+        .flat_map(|(&id, session)| {
+            session
+                .subnets
+                .iter()
+                .filter(move |net| net.contains(dst))
+                .map(move |net| (net.prefix(), id))
+        })
+        .max()
+        .map(|(_, id)| id)
+}
+
+/// Where a packet from a client goes in TUN mode: whether to the TUN
+/// device, and to which sessions.
+fn route_packet(
+    client_info: &mut TransientHashMap<Id, Session>,
+    packet: &[u8],
+    policy: &acl::Policy,
+    metrics: &mut metrics::Metrics,
+) -> (bool, Vec<Id>) {
+    let dst = lookup(client_info, packet).filter(|dst| client_info.contains_key(dst));
+    match dst {
+        Some(dst) if *policy != acl::Policy::Kernel => {
+            let src_ip = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+            let dst_ip = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+            if !policy.permits(src_ip, dst_ip) {
+                metrics.client_to_client_denied += 1;
+                return (false, Vec::new());
+            }
+            (false, vec![dst])
+        }
+        _ => (true, Vec::new()),
+    }
+}
+
+/// Where a frame goes in TAP mode, learning its source address if it comes
+/// from session `from`: whether to the TAP device, and to which sessions.
+/// Frames for multicast or unknown addresses are flooded.
+fn switch_frame(
+    client_info: &mut TransientHashMap<Id, Session>,
+    from: Option<Id>,
+    frame: &[u8],
+    policy: &acl::Policy,
+    metrics: &mut metrics::Metrics,
+) -> (bool, Vec<Id>) {
+    let (dst, src) = match (switch::destination(frame), switch::source(frame)) {
+        (Some(dst), Some(src)) => (dst, src),
+        _ => return (false, Vec::new()),
+    };
+    if let Some(id) = from {
+        let learned = match client_info.get_mut(&id) {
+            Some(session) => session.macs.learn(src),
+            None => false,
+        };
+        if learned {
+            // The address moved, e.g. a host behind one client to another.
+            let moved: Vec<Id> = client_info
+                .iter()
+                .filter(|&(&other, session)| other != id && session.macs.contains(&src))
+                .map(|(&other, _)| other)
+                .collect();
+            for other in moved {
+                client_info.get_mut(&other).unwrap().macs.forget(&src);
+            }
+        }
+    }
+    // Frames from one client to another are dropped if the policy denies it.
+    let isolated = from.is_some() && *policy == acl::Policy::Deny;
+    let owner = client_info
+        .iter()
+        .find(|&(_, session)| session.macs.contains(&dst))
+        .map(|(&id, _)| id);
+    match owner {
+        Some(owner) if Some(owner) == from => (false, Vec::new()),
+        Some(_) if isolated => {
+            metrics.client_to_client_denied += 1;
+            (false, Vec::new())
+        }
+        Some(owner) => (false, vec![owner]),
+        None if isolated => (true, Vec::new()),
+        None => {
+            let others = client_info
+                .keys()
+                .filter(|&&id| Some(id) != from)
+                .cloned()
+                .collect();
+            (from.is_some(), others)
+        }
+    }
 }
 
 const TUN: mio::Token = mio::Token(0);
 const SOCK: mio::Token = mio::Token(1);
+const CONTROL: mio::Token = mio::Token(2);
+const METRICS: mio::Token = mio::Token(3);
+/// The first of the tokens of connections to the control socket.
+const CONTROL_CONNECTIONS: mio::Token = mio::Token(16);
+/// The first of the tokens of connections to the metrics listener.
+const METRICS_CONNECTIONS: mio::Token = mio::Token(32);
+/// How long a client waits for the server to answer its request.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the server saves traffic for quotas.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Serves metrics on `addr`, or warns and goes on without them.
+fn listen_metrics(poll: &mio::Poll, addr: SocketAddr) -> Option<metrics::Listener> {
+    let result = metrics::Listener::bind(addr, METRICS_CONNECTIONS).and_then(|listener| {
+        poll.registry()
+            .register(
+                &mut mio::unix::SourceFd(&listener.as_raw_fd()),
+                METRICS,
+                mio::Interest::READABLE,
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(listener)
+    });
+    result
+        .map_err(|e| warn!("Unable to serve metrics on {}: {}", addr, e))
+        .ok()
+}
+
+/// Listens for `kytan status` and friends on `path`, or warns and goes on
+/// without a control socket, e.g. when not allowed to create it.
+fn listen_control(poll: &mio::Poll, path: &str) -> Option<control::Listener> {
+    let result = control::Listener::bind(path, CONTROL_CONNECTIONS).and_then(|listener| {
+        poll.registry()
+            .register(
+                &mut mio::unix::SourceFd(&listener.as_raw_fd()),
+                CONTROL,
+                mio::Interest::READABLE,
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(listener)
+    });
+    result
+        .map_err(|e| warn!("Running without a control socket: {}", e))
+        .ok()
+}
 
 fn resolve(host: &str) -> Result<IpAddr, String> {
     let ip_list = dns_lookup::lookup_host(host).map_err(|_| "dns_lookup::lookup_host")?;
     Ok(ip_list.first().unwrap().clone())
 }
 
-fn create_tun_attempt() -> device::Tun {
-    fn attempt(id: u8) -> device::Tun {
-        match id {
-            255 => panic!("Unable to create TUN device."),
-            _ => match device::Tun::create(id) {
-                Ok(tun) => tun,
-                Err(_) => attempt(id + 1),
-            },
-        }
+fn create_tun(name: Option<&str>, tap: bool) -> device::Tun {
+    let name = name.unwrap_or_else(|| device::default_name(tap));
+    match device::Tun::create(name, tap) {
+        Ok(tun) => tun,
+        Err(e) => panic!("Unable to create TUN device {}: {}", name, e),
     }
-    attempt(0)
 }
 
-fn derive_keys(password: &str) -> aead::LessSafeKey {
+/// Consumes the password so that it is zeroized as soon as the key is derived.
+fn derive_keys(password: Zeroizing<String>) -> aead::LessSafeKey {
     let mut key = [0; KEY_LEN];
     let salt = vec![0; 64];
     let pbkdf2_iterations: NonZeroU32 = NonZeroU32::new(1024).unwrap();
@@ -85,20 +769,29 @@ fn derive_keys(password: &str) -> aead::LessSafeKey {
     );
     let less_safe_key =
         aead::LessSafeKey::new(aead::UnboundKey::new(&aead::AES_256_GCM, &key).unwrap());
+    key.zeroize();
     less_safe_key
 }
 
 fn initiate(
     socket: &UdpSocket,
     addr: &SocketAddr,
-    secret: &str,
-) -> Result<(Id, Token, String), String> {
-    let key = derive_keys(secret);
-    let req_msg = Message::Request;
+    key: &aead::LessSafeKey,
+    name: &str,
+    subnets: &[utils::Ipv4Net],
+    tap: bool,
+    mtu: u32,
+) -> Result<(Id, Token, dns::Settings, utils::Routing, u32), String> {
+    let req_msg = Message::Request {
+        name: String::from(name),
+        subnets: subnets.to_vec(),
+        tap,
+        mtu,
+    };
     let encoded_req_msg: Vec<u8> = serialize(&req_msg).map_err(|e| e.to_string())?;
     let mut encrypted_req_msg = encoded_req_msg.clone();
     encrypted_req_msg.resize(encoded_req_msg.len() + key.algorithm().tag_len(), 0);
-    let (aad, nonce) = generate_add_nonce(secret);
+    let (aad, nonce) = generate_add_nonce();
     key.seal_in_place_append_tag(nonce, aad, &mut encrypted_req_msg)
         .unwrap();
 
@@ -112,42 +805,93 @@ fn initiate(
     info!("Request sent to {}.", addr);
 
     let mut buf = [0u8; 1600];
-    let (len, recv_addr) = socket.recv_from(&mut buf).map_err(|e| e.to_string())?;
+    socket
+        .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+        .map_err(|e| e.to_string())?;
+    let received = socket.recv_from(&mut buf);
+    socket.set_read_timeout(None).map_err(|e| e.to_string())?;
+    let (len, recv_addr) = received.map_err(|e| match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+            format!("No response from {} within {:?}", addr, HANDSHAKE_TIMEOUT)
+        }
+        _ => e.to_string(),
+    })?;
     assert_eq!(&recv_addr, addr);
     info!("Response received from {}.", addr);
 
-    let (aad, nonce) = generate_add_nonce(secret);
+    let (aad, nonce) = generate_add_nonce();
     let decrypted_buf = key.open_in_place(nonce, aad, &mut buf[0..len]).unwrap();
 
     let dlen = decrypted_buf.len();
     let resp_msg: Message = deserialize(&decrypted_buf[0..dlen]).map_err(|e| e.to_string())?;
     match resp_msg {
-        Message::Response { id, token, dns } => Ok((id, token, dns)),
+        Message::Response {
+            id,
+            token,
+            dns,
+            routing,
+            mtu,
+        } => Ok((id, token, dns, routing, mtu)),
+        Message::Disconnect { token: _, reason } => Err(format!("Rejected by server: {}", reason)),
         _ => Err(format!("Invalid message {:?} from {}", resp_msg, addr)),
     }
 }
 
-pub fn connect(host: &str, port: u16, default: bool, secret: &str) {
+pub fn connect(client: cli::Client) {
     info!("Working in client mode.");
-    let remote_ip = resolve(host).unwrap();
-    let remote_addr = SocketAddr::new(remote_ip, port);
+    // Dropped last, once every change to the system has been undone.
+    let _journal = match journal::open(Path::new(journal::DEFAULT_PATH)) {
+        Ok(guard) => Some(guard),
+        Err(e) => {
+            warn!("Unable to open journal: {}. Changes will not survive a crash.", e);
+            None
+        }
+    };
+    let key = derive_keys(client.key);
+    let remote_ip = resolve(&client.remote_addr).unwrap();
+    let remote_addr = SocketAddr::new(remote_ip, client.port);
     info!("Remote server: {}", remote_addr);
 
     let local_addr: SocketAddr = "0.0.0.0:0".parse::<SocketAddr>().unwrap();
     let socket = UdpSocket::bind(&local_addr).unwrap();
+    if let Some(mark) = client.fwmark {
+        utils::set_mark(socket.as_raw_fd(), mark).unwrap();
+    }
 
-    let key = derive_keys(secret);
-
-    let (id, token, dns) = initiate(&socket, &remote_addr, &secret).unwrap();
+    let (id, token, dns, routing, mtu) = initiate(
+        &socket,
+        &remote_addr,
+        &key,
+        &client.name,
+        &client.subnets,
+        client.tap,
+        client.mtu,
+    )
+    .unwrap();
+    if mtu < client.mtu {
+        info!("Using the server's MTU of {}.", mtu);
+    }
     info!(
-        "Session established with token {}. Assigned IP address: 10.10.10.{}. dns: {}",
+        "Session established with token {}. Assigned IP address: 10.10.10.{}. dns: {:?}",
         token, id, dns
     );
+    if !client.subnets.is_empty() {
+        info!("Enabling kernel's IPv4 forwarding for {:?}.", client.subnets);
+        utils::enable_ipv4_forwarding().unwrap();
+    }
+    let default_route = routing.default_route.unwrap_or(client.default_route);
+    let mut routes = client.routes.clone();
+    for route in routing.routes {
+        info!("Route pushed by server: {}", route);
+        if !routes.contains(&route) {
+            routes.push(route);
+        }
+    }
 
     info!("Bringing up TUN device.");
-    let mut tun = create_tun_attempt();
+    let mut tun = create_tun(client.interface.as_deref(), client.tap);
     let tun_rawfd = tun.as_raw_fd();
-    tun.up(id);
+    tun.up(id, mtu);
     let mut tunfd = mio::unix::SourceFd(&tun_rawfd);
     info!(
         "TUN device {} initialized. Internal IP: 10.10.10.{}/24.",
@@ -155,8 +899,14 @@ pub fn connect(host: &str, port: u16, default: bool, secret: &str) {
         id
     );
 
-    info!("setting dns to {}", dns);
-    utils::set_dns(&dns).unwrap();
+    // RAII so ignore unused variable warning
+    let _dns = match dns::DnsConfig::create(client.dns_backend, tun.name(), &dns) {
+        Ok(dns) => Some(dns),
+        Err(e) => {
+            warn!("Unable to configure DNS: {}", e);
+            None
+        }
+    };
 
     let mut poll = mio::Poll::new().unwrap();
     info!("Setting up TUN device for polling.");
@@ -174,55 +924,149 @@ pub fn connect(host: &str, port: u16, default: bool, secret: &str) {
         .register(&mut sockfd, SOCK, mio::Interest::READABLE)
         .unwrap();
 
+    let mut metrics = metrics::Metrics::default();
+    metrics.handshakes_accepted += 1;
+    let mut metrics_listener = client.metrics.and_then(|addr| listen_metrics(&poll, addr));
+
     let mut events = mio::Events::with_capacity(1024);
     let mut buf = [0u8; 1600];
 
     // RAII so ignore unused variable warning
-    let _gw =
-        utils::DefaultGateway::create("10.10.10.1", &format!("{}", remote_addr.ip()), default);
+    let _kill_switch = if client.kill_switch {
+        Some(firewall::KillSwitch::create(tun.name(), remote_addr, &client.excludes).unwrap())
+    } else {
+        None
+    };
+    // RAII so ignore unused variable warning
+    let _gw = match client.fwmark {
+        None => Some(utils::DefaultGateway::create(
+            "10.10.10.1",
+            &format!("{}", remote_addr.ip()),
+            default_route,
+            &client.excludes,
+        )
+        .unwrap()),
+        Some(_) => None,
+    };
+    // RAII so ignore unused variable warning
+    let _policy = match client.fwmark {
+        Some(mark) if default_route => {
+            let gateway = if client.tap { Some("10.10.10.1") } else { None };
+            let policy = utils::PolicyRouting::create(tun.name(), gateway, mark, &client.excludes);
+            Some(policy.unwrap())
+        }
+        _ => None,
+    };
+    let _routes = utils::Routes::create("10.10.10.1", &routes).unwrap();
+
+    let hook_env = hook::Env {
+        interface: String::from(tun.name()),
+        address: format!("10.10.10.{}", id),
+        endpoint: remote_addr.to_string(),
+        identity: client.name.clone(),
+    };
+    if let Some(ref up) = client.up {
+        hook::run(up, "up", &hook_env).unwrap();
+    }
+    // RAII so ignore unused variable warning
+    let _down = hook::Down::new(client.down.clone(), hook_env);
+
+    // The routes, DNS settings and firewall rules are restored by the parent,
+    // which stays privileged. The child keeps CAP_NET_ADMIN to change the MTU.
+    let sandboxed = !client.sandbox.is_empty();
+    if sandboxed && !client.sandbox.fork(&[privilege::Capability::NetAdmin]).unwrap() {
+        return;
+    }
 
     let mut encoder = snap::raw::Encoder::new();
     let mut decoder = snap::raw::Decoder::new();
 
+    let mut discovery = if client.pmtu_discovery {
+        Some(pmtu::Discovery::new(device::MIN_MTU, mtu, Instant::now()))
+    } else {
+        None
+    };
+    let mut tun_mtu = mtu;
+
     CONNECTED.store(true, Ordering::Relaxed);
     info!("Ready for transmission.");
 
-    loop {
+    'transmit: loop {
         if INTERRUPTED.load(Ordering::Relaxed) {
             break;
         }
-        poll.poll(&mut events, None).unwrap();
+        let timeout = discovery
+            .as_ref()
+            .map(|discovery| discovery.deadline().saturating_duration_since(Instant::now()));
+        if let Err(e) = poll.poll(&mut events, timeout) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            panic!("poll: {}", e);
+        }
         for event in events.iter() {
             match event.token() {
                 SOCK => {
                     let (len, addr) = sockfd.recv_from(&mut buf).unwrap();
-                    let (aad, nonce) = generate_add_nonce(secret);
-
-                    let decrypted_buf = key.open_in_place(nonce, aad, &mut buf[0..len]).unwrap();
-                    let dlen = decrypted_buf.len();
-                    let msg: Message = deserialize(&decrypted_buf[0..dlen]).unwrap();
+                    let msg = match open(&key, &mut buf[0..len], &mut metrics) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
                     match msg {
-                        Message::Request
+                        Message::Request {
+                            name: _,
+                            subnets: _,
+                            tap: _,
+                            mtu: _,
+                        }
                         | Message::Response {
                             id: _,
                             token: _,
                             dns: _,
+                            routing: _,
+                            mtu: _,
+                        }
+                        | Message::Probe {
+                            id: _,
+                            token: _,
+                            seq: _,
+                            padding: _,
+                        }
+                        | Message::Mtu {
+                            id: _,
+                            token: _,
+                            mtu: _,
                         } => {
                             warn!("Invalid message {:?} from {}", msg, addr);
                         }
+                        Message::Disconnect {
+                            token: server_token,
+                            reason,
+                        } => {
+                            if token == server_token {
+                                warn!("Disconnected by server: {}", reason);
+                                break 'transmit;
+                            }
+                        }
+                        Message::ProbeAck {
+                            token: server_token,
+                            seq,
+                        } => {
+                            if let Some(ref mut discovery) = discovery {
+                                if token == server_token {
+                                    discovery.ack(seq);
+                                }
+                            }
+                        }
                         Message::Data {
                             id: _,
                             token: server_token,
                             data,
                         } => {
                             if token == server_token {
-                                let decompressed_data = decoder.decompress_vec(&data).unwrap();
-                                let data_len = decompressed_data.len();
-                                let mut sent_len = 0;
-                                while sent_len < data_len {
-                                    sent_len +=
-                                        tun.write(&decompressed_data[sent_len..data_len]).unwrap();
-                                }
+                                metrics.bytes_in += len as u64;
+                                metrics.packets_in += 1;
+                                deliver(&mut tun, &mut decoder, &data, &mut metrics);
                             } else {
                                 warn!(
                                     "Token mismatched. Received: {}. Expected: {}",
@@ -243,7 +1087,7 @@ pub fn connect(host: &str, port: u16, default: bool, secret: &str) {
                     let encoded_msg = serialize(&msg).unwrap();
                     let mut encrypted_msg = encoded_msg.clone();
                     encrypted_msg.resize(encoded_msg.len() + key.algorithm().tag_len(), 0);
-                    let (aad, nonce) = generate_add_nonce(secret);
+                    let (aad, nonce) = generate_add_nonce();
                     key.seal_in_place_append_tag(nonce, aad, &mut encrypted_msg)
                         .unwrap();
                     let mut sent_len = 0;
@@ -252,29 +1096,76 @@ pub fn connect(host: &str, port: u16, default: bool, secret: &str) {
                             .send_to(&encrypted_msg[sent_len..encrypted_msg.len()], remote_addr)
                             .unwrap();
                     }
+                    metrics.bytes_out += sent_len as u64;
+                    metrics.packets_out += 1;
+                }
+                METRICS => {
+                    if let Some(ref mut listener) = metrics_listener {
+                        listener.accept(poll.registry());
+                    }
+                }
+                token if metrics_listener.iter().any(|l| l.owns(token)) => {
+                    let listener = metrics_listener.as_mut().unwrap();
+                    listener.ready(poll.registry(), token, || {
+                        metrics.render(&[("sessions", "Active sessions.", 1)])
+                    });
                 }
                 _ => unreachable!(),
             }
         }
+        if let Some(ref mut discovery) = discovery {
+            if let Some((seq, probe_mtu)) = discovery.probe(Instant::now()) {
+                let len = datagram_len(probe_mtu, client.tap);
+                send_probe(&sockfd, remote_addr, &seal_probe(&key, id, token, seq, len));
+            }
+            if let Some(found) = discovery.result() {
+                if found != tun_mtu {
+                    info!("Path MTU discovery: changing the MTU to {}.", found);
+                    match tun.set_mtu(found) {
+                        Ok(()) => tun_mtu = found,
+                        Err(e) => warn!("Unable to change the MTU: {}", e),
+                    }
+                }
+                // Sent after every search in case an earlier one was lost.
+                let msg = Message::Mtu {
+                    id,
+                    token,
+                    mtu: tun_mtu,
+                };
+                if let Err(e) = sockfd.send_to(&seal(&key, &msg), remote_addr) {
+                    warn!("Unable to send MTU to server: {}", e);
+                }
+            }
+        }
+    }
+    if sandboxed {
+        process::exit(0);
     }
 }
 
-pub fn serve(port: u16, secret: &str, dns: IpAddr) {
+pub fn serve(mut server: cli::Server) {
     if cfg!(not(target_os = "linux")) {
         panic!("Server mode is only available in Linux!");
     }
 
     info!("Working in server mode.");
+    let key = derive_keys(server.key);
 
-    let public_ip = utils::get_public_ip().unwrap();
-    info!("Public IP: {}", public_ip);
+    match server.public_addr {
+        Some(addr) => info!("Public IP: {}", addr),
+        None => match utils::get_public_ips() {
+            Ok(ref addrs) if !addrs.is_empty() => info!("Public IP: {:?}", addrs),
+            Ok(_) => info!("No public IP on local interfaces. The server may be behind NAT."),
+            Err(e) => warn!("Unable to detect public IP: {}", e),
+        },
+    }
 
     info!("Enabling kernel's IPv4 forwarding.");
     utils::enable_ipv4_forwarding().unwrap();
 
     info!("Bringing up TUN device.");
-    let mut tun = create_tun_attempt();
-    tun.up(1);
+    let mut tun = create_tun(server.interface.as_deref(), server.tap);
+    tun.up(1, server.mtu);
 
     let tun_rawfd = tun.as_raw_fd();
     let mut tunfd = mio::unix::SourceFd(&tun_rawfd);
@@ -283,9 +1174,9 @@ pub fn serve(port: u16, secret: &str, dns: IpAddr) {
         tun.name()
     );
 
-    let addr = format!("0.0.0.0:{}", port).parse().unwrap();
+    let addr = SocketAddr::new(server.bind_addr, server.port);
     let mut sockfd = mio::net::UdpSocket::bind(addr).unwrap();
-    info!("Listening on: 0.0.0.0:{}.", port);
+    info!("Listening on: {}.", addr);
 
     let mut poll = mio::Poll::new().unwrap();
     poll.registry()
@@ -295,17 +1186,40 @@ pub fn serve(port: u16, secret: &str, dns: IpAddr) {
         .register(&mut tunfd, TUN, mio::Interest::READABLE)
         .unwrap();
 
+    let mut control = listen_control(&poll, &server.control);
+
+    let mut metrics = metrics::Metrics::default();
+    let mut metrics_listener = server.metrics.and_then(|addr| listen_metrics(&poll, addr));
+
     let mut events = mio::Events::with_capacity(1024);
 
     let mut rng = thread_rng();
-    let mut available_ids: Vec<Id> = (2..254).collect();
-    let mut client_info: TransientHashMap<Id, (Token, SocketAddr)> = TransientHashMap::new(60);
+    let mut leases = Leases::new(&server.peers);
+    let mut bans = Bans::default();
+    let mut accounting = match quota::Accounting::open(Path::new(&server.quota_state)) {
+        Ok(accounting) => accounting,
+        Err(e) => {
+            warn!("Traffic will not be saved: {}", e);
+            quota::Accounting::default()
+        }
+    };
+    let mut saved = Instant::now();
+    let mut hooks = hook::Sessions::new(server.on_connect.clone(), server.on_disconnect.clone());
+    let mut client_info: TransientHashMap<Id, Session> = TransientHashMap::new(60);
 
     let mut buf = [0u8; 1600];
     let mut encoder = snap::raw::Encoder::new();
     let mut decoder = snap::raw::Decoder::new();
 
-    let key = derive_keys(secret);
+    server
+        .sandbox
+        .apply(&[privilege::Capability::NetAdmin])
+        .unwrap();
+    // The route command is out of reach after chroot.
+    let mtu_routes = server.sandbox.chroot.is_none();
+    if !mtu_routes {
+        info!("Not limiting the MTU of clients with routes after chroot.");
+    }
 
     LISTENING.store(true, Ordering::Relaxed);
     info!("Ready for transmission.");
@@ -315,23 +1229,228 @@ pub fn serve(port: u16, secret: &str, dns: IpAddr) {
             break;
         }
 
+        if RELOAD.swap(false, Ordering::Relaxed) {
+            info!("Reloading configuration.");
+            let reloaded = if server.sandbox.chroot.is_some() {
+                Err(String::from("the configuration file is out of reach after chroot"))
+            } else {
+                cli::reload_server()
+            };
+            match reloaded {
+                Ok(reloaded) => {
+                    server.dns = reloaded.dns;
+                    server.routing = reloaded.routing;
+                    server.client_to_client = reloaded.client_to_client;
+                    server.peers = reloaded.peers;
+                    server.quota = reloaded.quota;
+                    server.rate = reloaded.rate;
+                    hooks.on_connect = reloaded.on_connect;
+                    hooks.on_disconnect = reloaded.on_disconnect;
+                    let peers = &server.peers;
+                    let ids: Vec<Id> = client_info.keys().cloned().collect();
+                    for id in ids {
+                        let session = client_info.get_mut(&id).unwrap();
+                        let peer = peers.iter().find(|peer| peer.name == session.name);
+                        session.identity = identity(peer, session.addr);
+                        session.quota = peer_quota(server.quota, peer);
+                        let rate = peer_rate(server.rate, peer);
+                        if session.rate != rate {
+                            session.set_rate(rate);
+                        }
+                    }
+                    let revoked: Vec<Id> = client_info
+                        .iter()
+                        .filter(|&(_, session)| {
+                            let peer = peers.iter().find(|peer| peer.name == session.name);
+                            !is_authorized(peers, &session.name)
+                                || check_subnets(peer, &session.subnets).is_err()
+                        })
+                        .map(|(&id, _)| id)
+                        .collect();
+                    for id in revoked {
+                        close_session(
+                            &mut client_info,
+                            &mut leases,
+                            &mut hooks,
+                            &sockfd,
+                            &key,
+                            id,
+                            "peer no longer authorized",
+                        );
+                    }
+                    for id in misplaced_sessions(&client_info, peers) {
+                        close_session(
+                            &mut client_info,
+                            &mut leases,
+                            &mut hooks,
+                            &sockfd,
+                            &key,
+                            id,
+                            "address reassigned",
+                        );
+                    }
+                    let in_use: Vec<Id> = client_info.keys().cloned().collect();
+                    leases.update(&server.peers, &in_use);
+                }
+                Err(e) => warn!("Unable to reload configuration: {}", e),
+            }
+        }
+
         // Clear expired client info
-        available_ids.append(&mut client_info.prune());
-        poll.poll(&mut events, None).unwrap();
+        for id in client_info.prune() {
+            leases.release(id);
+            hooks.disconnected(id, "expired");
+        }
+        hooks.reap();
+        if saved.elapsed() >= SAVE_INTERVAL {
+            accounting.prune(control::unix_time(SystemTime::now()));
+            if let Err(e) = accounting.save() {
+                warn!("Unable to save traffic: {}", e);
+            }
+            saved = Instant::now();
+        }
+        // Wake up in time to save even when no packets arrive.
+        let timeout = SAVE_INTERVAL.saturating_sub(saved.elapsed());
+        if let Err(e) = poll.poll(&mut events, Some(timeout)) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            panic!("poll: {}", e);
+        }
         for event in events.iter() {
             match event.token() {
                 SOCK => {
                     let (len, addr) = sockfd.recv_from(&mut buf).unwrap();
-                    let (aad, nonce) = generate_add_nonce(secret);
-                    let decrypted_buf = key.open_in_place(nonce, aad, &mut buf[0..len]).unwrap();
-                    let dlen = decrypted_buf.len();
-                    let msg: Message = deserialize(&decrypted_buf[0..dlen]).unwrap();
+                    let msg = match open(&key, &mut buf[0..len], &mut metrics) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
                     match msg {
-                        Message::Request => {
-                            let client_id: Id = available_ids.pop().unwrap();
-                            let client_token: Token = rng.gen::<Token>();
-
-                            client_info.insert(client_id, (client_token, addr));
+                        Message::Request {
+                            name,
+                            subnets,
+                            tap,
+                            mtu,
+                        } => {
+                            if !is_authorized(&server.peers, &name) {
+                                let reason = format!("unknown peer {:?}", name);
+                                reject(&sockfd, &key, addr, reason);
+                                metrics.handshakes_rejected += 1;
+                                continue;
+                            }
+                            if tap != server.tap {
+                                let mode = if server.tap { "TAP" } else { "TUN" };
+                                let reason = format!("server runs in {} mode", mode);
+                                reject(&sockfd, &key, addr, reason);
+                                metrics.handshakes_rejected += 1;
+                                continue;
+                            }
+                            if bans.contains(&name, addr.ip()) {
+                                reject(&sockfd, &key, addr, String::from("banned"));
+                                metrics.handshakes_rejected += 1;
+                                continue;
+                            }
+                            let peer = server.peers.iter().find(|peer| peer.name == name);
+                            let quota = peer_quota(server.quota, peer);
+                            let now = control::unix_time(SystemTime::now());
+                            if let Some(reason) =
+                                accounting.exceeded(&identity(peer, addr), &quota, now)
+                            {
+                                if quota.action == quota::Action::Disconnect {
+                                    reject(&sockfd, &key, addr, reason);
+                                    metrics.handshakes_rejected += 1;
+                                    continue;
+                                }
+                            }
+                            if let Err(reason) = check_subnets(peer, &subnets) {
+                                reject(&sockfd, &key, addr, reason);
+                                metrics.handshakes_rejected += 1;
+                                continue;
+                            }
+                            let replaced = match replaced_sessions(
+                                &client_info,
+                                &subnets,
+                                &identity(peer, addr),
+                            ) {
+                                Ok(replaced) => replaced,
+                                Err(reason) => {
+                                    reject(&sockfd, &key, addr, reason);
+                                    metrics.handshakes_rejected += 1;
+                                    continue;
+                                }
+                            };
+                            for id in replaced {
+                                client_info.remove(&id);
+                                leases.release(id);
+                                hooks.disconnected(id, "replaced by a new session");
+                            }
+                            let client_id: Id = match leases.acquire(peer) {
+                                Some(id) => id,
+                                None => {
+                                    reject(&sockfd, &key, addr, String::from("no address left"));
+                                    metrics.handshakes_rejected += 1;
+                                    continue;
+                                }
+                            };
+                            // A reserved address may still be held by an
+                            // earlier session of this client, which is
+                            // replaced, or by the session of another client.
+                            let held_by = client_info
+                                .get(&client_id)
+                                .map(|session| session.identity.clone());
+                            match held_by {
+                                Some(ref held_by) if *held_by == identity(peer, addr) => {
+                                    client_info.remove(&client_id);
+                                    leases.release(client_id);
+                                    hooks.disconnected(client_id, "replaced by a new session");
+                                }
+                                Some(_) => {
+                                    close_session(
+                                        &mut client_info,
+                                        &mut leases,
+                                        &mut hooks,
+                                        &sockfd,
+                                        &key,
+                                        client_id,
+                                        "address reserved for another peer",
+                                    );
+                                }
+                                None => (),
+                            }
+                            let mut session = Session::new(
+                                rng.gen::<Token>(),
+                                addr,
+                                name,
+                                identity(peer, addr),
+                                quota,
+                                peer_rate(server.rate, peer),
+                            );
+                            if let Err(e) = session.route_subnets(client_id, subnets) {
+                                warn!("Unable to route subnets of {}: {}", addr, e);
+                                leases.release(client_id);
+                                let reason = String::from("unable to route subnets");
+                                reject(&sockfd, &key, addr, reason);
+                                metrics.handshakes_rejected += 1;
+                                continue;
+                            }
+                            let mtu = cmp::max(cmp::min(mtu, server.mtu), device::MIN_MTU);
+                            if let Err(e) = session.set_mtu(
+                                tun.name(),
+                                client_id,
+                                mtu,
+                                server.mtu,
+                                mtu_routes,
+                            ) {
+                                warn!("Unable to set MTU of 10.10.10.{}: {}", client_id, e);
+                            }
+                            let client_token = session.token;
+                            let hook_env = hook::Env {
+                                interface: String::from(tun.name()),
+                                address: format!("10.10.10.{}", client_id),
+                                endpoint: addr.to_string(),
+                                identity: session.identity.clone(),
+                            };
+                            client_info.insert(client_id, session);
 
                             info!(
                                 "Got request from {}. Assigning IP address: 10.10.10.{}.",
@@ -341,13 +1460,15 @@ pub fn serve(port: u16, secret: &str, dns: IpAddr) {
                             let reply = Message::Response {
                                 id: client_id,
                                 token: client_token,
-                                dns: dns.to_string(),
+                                dns: server.dns.clone(),
+                                routing: server.routing.clone(),
+                                mtu,
                             };
                             let encoded_reply = serialize(&reply).unwrap();
                             let mut encrypted_reply = encoded_reply.clone();
                             encrypted_reply
                                 .resize(encoded_reply.len() + key.algorithm().tag_len(), 0);
-                            let (aad, nonce) = generate_add_nonce(secret);
+                            let (aad, nonce) = generate_add_nonce();
                             key.seal_in_place_append_tag(nonce, aad, &mut encrypted_reply)
                                 .unwrap();
                             let mut sent_len = 0;
@@ -359,76 +1480,329 @@ pub fn serve(port: u16, secret: &str, dns: IpAddr) {
                                     )
                                     .unwrap();
                             }
+                            metrics.handshakes_accepted += 1;
+                            hooks.connected(client_id, hook_env);
                         }
                         Message::Response {
                             id: _,
                             token: _,
                             dns: _,
-                        } => warn!("Invalid message {:?} from {}", msg, addr),
-                        Message::Data { id, token, data } => match client_info.get(&id) {
-                            None => warn!("Unknown data with token {} from id {}.", token, id),
-                            Some(&(t, _)) => {
-                                if t != token {
-                                    warn!(
-                                        "Unknown data with mismatched token {} from id {}. \
-                                               Expected: {}",
-                                        token, id, t
+                            routing: _,
+                            mtu: _,
+                        }
+                        | Message::Disconnect {
+                            token: _,
+                            reason: _,
+                        }
+                        | Message::ProbeAck { token: _, seq: _ } => {
+                            warn!("Invalid message {:?} from {}", msg, addr)
+                        }
+                        Message::Probe {
+                            id,
+                            token,
+                            seq,
+                            padding: _,
+                        } => match client_info.get(&id) {
+                            Some(session) if session.token == token => {
+                                let ack = Message::ProbeAck {
+                                    token,
+                                    seq,
+                                };
+                                if let Err(e) = sockfd.send_to(&seal(&key, &ack), addr) {
+                                    warn!("Unable to acknowledge probe from {}: {}", addr, e);
+                                }
+                            }
+                            _ => warn!("Unknown probe with token {} from id {}.", token, id),
+                        },
+                        Message::Mtu { id, token, mtu } => match client_info.get_mut(&id) {
+                            Some(session) if session.token == token => {
+                                let mtu = cmp::max(cmp::min(mtu, server.mtu), device::MIN_MTU);
+                                if mtu != session.mtu {
+                                    info!("Path MTU of 10.10.10.{} is {}.", id, mtu);
+                                    if let Err(e) = session.set_mtu(
+                                        tun.name(),
+                                        id,
+                                        mtu,
+                                        server.mtu,
+                                        mtu_routes,
+                                    ) {
+                                        warn!("Unable to set MTU of 10.10.10.{}: {}", id, e);
+                                    }
+                                }
+                            }
+                            _ => warn!("Unknown MTU with token {} from id {}.", token, id),
+                        },
+                        Message::Data { id, token, data } => {
+                            let mut packet = None;
+                            let verdict = match client_info.get_mut(&id) {
+                                None => {
+                                    warn!("Unknown data with token {} from id {}.", token, id);
+                                    continue;
+                                }
+                                Some(session) => {
+                                    if session.token != token {
+                                        warn!(
+                                            "Unknown data with mismatched token {} from id {}. \
+                                                   Expected: {}",
+                                            token, id, session.token
+                                        );
+                                        continue;
+                                    }
+                                    // Rate limits and quotas count the packet
+                                    // itself, as for packets to the client.
+                                    let p = match decompress(&mut decoder, &data, &mut metrics) {
+                                        Some(p) => p,
+                                        None => continue,
+                                    };
+                                    if let Some(ref mut upload) = session.upload {
+                                        if !upload.take(p.len() as u64, Instant::now()) {
+                                            session.dropped_in += 1;
+                                            metrics.rate_limited_in += 1;
+                                            continue;
+                                        }
+                                    }
+                                    let now = SystemTime::now();
+                                    let verdict = accounting.record(
+                                        &session.identity,
+                                        &session.quota,
+                                        p.len() as u64,
+                                        true,
+                                        control::unix_time(now),
                                     );
+                                    if let quota::Verdict::Forward = verdict {
+                                        session.bytes_in += len as u64;
+                                        session.last_packet = Some(now);
+                                        metrics.bytes_in += len as u64;
+                                        metrics.packets_in += 1;
+                                        if !server.tap && !session.sends_from(id, &p) {
+                                            session.dropped_in += 1;
+                                            metrics.spoofed_in += 1;
+                                        } else {
+                                            packet = Some(p);
+                                        }
+                                    }
+                                    verdict
+                                }
+                            };
+                            if let Some(packet) = packet {
+                                let (local, targets) = if server.tap {
+                                    switch_frame(
+                                        &mut client_info,
+                                        Some(id),
+                                        &packet,
+                                        &server.client_to_client,
+                                        &mut metrics,
+                                    )
                                 } else {
-                                    let decompressed_data = decoder.decompress_vec(&data).unwrap();
-                                    let data_len = decompressed_data.len();
-                                    let mut sent_len = 0;
-                                    while sent_len < data_len {
-                                        sent_len += tun
-                                            .write(&decompressed_data[sent_len..data_len])
-                                            .unwrap();
+                                    route_packet(
+                                        &mut client_info,
+                                        &packet,
+                                        &server.client_to_client,
+                                        &mut metrics,
+                                    )
+                                };
+                                if local {
+                                    write_tun(&mut tun, &packet, &mut metrics);
+                                }
+                                for dst in targets {
+                                    let session = client_info.get_mut(&dst).unwrap();
+                                    match session.admit_out(
+                                        packet.len() as u64,
+                                        &mut accounting,
+                                        &mut metrics,
+                                    ) {
+                                        quota::Verdict::Forward => send_data(
+                                            &sockfd,
+                                            &key,
+                                            &mut encoder,
+                                            session,
+                                            dst,
+                                            &packet,
+                                            &mut metrics,
+                                        ),
+                                        quota::Verdict::Drop => {}
+                                        quota::Verdict::Disconnect(reason) => {
+                                            close_session(
+                                                &mut client_info,
+                                                &mut leases,
+                                                &mut hooks,
+                                                &sockfd,
+                                                &key,
+                                                dst,
+                                                &reason,
+                                            );
+                                        }
                                     }
                                 }
                             }
-                        },
+                            if let quota::Verdict::Disconnect(reason) = verdict {
+                                close_session(
+                                    &mut client_info,
+                                    &mut leases,
+                                    &mut hooks,
+                                    &sockfd,
+                                    &key,
+                                    id,
+                                    &reason,
+                                );
+                            }
+                        }
                     }
                 }
                 TUN => {
                     let len: usize = tun.read(&mut buf).unwrap();
                     let data = &buf[0..len];
-                    let client_id: u8 = data[19];
+                    let targets = if server.tap {
+                        let policy = &server.client_to_client;
+                        switch_frame(&mut client_info, None, data, policy, &mut metrics).1
+                    } else {
+                        match lookup(&client_info, data) {
+                            Some(id) => vec![id],
+                            None => {
+                                warn!("Unroutable IP packet from TUN.");
+                                continue;
+                            }
+                        }
+                    };
 
-                    match client_info.get(&client_id) {
-                        None => warn!("Unknown IP packet from TUN for client {}.", client_id),
-                        Some(&(token, addr)) => {
-                            let msg = Message::Data {
-                                id: client_id,
-                                token: token,
-                                data: encoder.compress_vec(data).unwrap(),
+                    for client_id in targets {
+                        let session = match client_info.get_mut(&client_id) {
+                            None => {
+                                warn!("Unknown IP packet from TUN for client {}.", client_id);
+                                continue;
+                            }
+                            Some(session) => session,
+                        };
+                        match session.admit_out(len as u64, &mut accounting, &mut metrics) {
+                            quota::Verdict::Forward => send_data(
+                                &sockfd,
+                                &key,
+                                &mut encoder,
+                                session,
+                                client_id,
+                                data,
+                                &mut metrics,
+                            ),
+                            quota::Verdict::Drop => {}
+                            quota::Verdict::Disconnect(reason) => {
+                                close_session(
+                                    &mut client_info,
+                                    &mut leases,
+                                    &mut hooks,
+                                    &sockfd,
+                                    &key,
+                                    client_id,
+                                    &reason,
+                                );
+                            }
+                        }
+                    }
+                }
+                CONTROL => {
+                    if let Some(ref mut control) = control {
+                        control.accept(poll.registry());
+                    }
+                }
+                token if control.iter().any(|c| c.owns(token)) => {
+                    let control = control.as_mut().unwrap();
+                    control.ready(poll.registry(), token, |request| match request {
+                        control::Request::Status => control::Response::Status(
+                            client_info
+                                .iter()
+                                .map(|(&id, session)| session.info(id))
+                                .collect(),
+                        ),
+                        control::Request::Usage => control::Response::Usage(
+                            accounting.info(control::unix_time(SystemTime::now())),
+                        ),
+                        control::Request::Kick { target, ban } => {
+                            let kicked: Vec<Id> = client_info
+                                .iter()
+                                .filter(|&(&id, session)| session.matches(id, &target))
+                                .map(|(&id, _)| id)
+                                .collect();
+                            if kicked.is_empty() && !ban {
+                                return control::Response::Error(format!(
+                                    "no session matches {}",
+                                    target
+                                ));
+                            }
+                            let reason = if ban {
+                                "banned by administrator"
+                            } else {
+                                "disconnected by administrator"
                             };
-                            let encoded_msg = serialize(&msg).unwrap();
-                            let mut encrypted_msg = encoded_msg.clone();
-                            encrypted_msg.resize(encoded_msg.len() + key.algorithm().tag_len(), 0);
-                            let (aad, nonce) = generate_add_nonce(secret);
-                            key.seal_in_place_append_tag(nonce, aad, &mut encrypted_msg)
+                            for &id in &kicked {
+                                let session = close_session(
+                                    &mut client_info,
+                                    &mut leases,
+                                    &mut hooks,
+                                    &sockfd,
+                                    &key,
+                                    id,
+                                    reason,
+                                )
                                 .unwrap();
-                            let mut sent_len = 0;
-                            while sent_len < encrypted_msg.len() {
-                                sent_len += sockfd
-                                    .send_to(&encrypted_msg[sent_len..encrypted_msg.len()], addr)
-                                    .unwrap();
+                                if ban {
+                                    // A name alone would let the client back in without one.
+                                    bans.add(&session.addr.ip().to_string());
+                                    if !session.name.is_empty() {
+                                        bans.add(&session.name);
+                                    }
+                                }
+                            }
+                            if ban && kicked.is_empty() {
+                                bans.add(&target);
+                            }
+                            control::Response::Done(format!(
+                                "{} session(s) closed{}",
+                                kicked.len(),
+                                if ban { ", banned" } else { "" }
+                            ))
+                        }
+                        control::Request::Unban { target } => {
+                            if bans.remove(&target) {
+                                info!("Lifted ban on {}.", target);
+                                control::Response::Done(format!("{} unbanned", target))
+                            } else {
+                                control::Response::Error(format!("{} is not banned", target))
                             }
                         }
+                    })
+                }
+                METRICS => {
+                    if let Some(ref mut listener) = metrics_listener {
+                        listener.accept(poll.registry());
                     }
                 }
+                token if metrics_listener.iter().any(|l| l.owns(token)) => {
+                    let listener = metrics_listener.as_mut().unwrap();
+                    listener.ready(poll.registry(), token, || {
+                        metrics.render(&[
+                            ("sessions", "Active sessions.", client_info.len() as u64),
+                            (
+                                "address_pool_available",
+                                "Tunnel addresses left to hand out.",
+                                leases.available.len() as u64,
+                            ),
+                        ])
+                    });
+                }
                 _ => unreachable!(),
             }
         }
     }
+    if let Err(e) = accounting.save() {
+        warn!("Unable to save traffic: {}", e);
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::network::*;
-    use std::net::Ipv4Addr;
 
     #[cfg(target_os = "linux")]
-    use std::thread;
+    use std::{thread, time};
 
     #[test]
     fn resolve_test() {
@@ -438,12 +1812,301 @@ mod tests {
         );
     }
 
+    #[test]
+    fn leases_test() {
+        let peers = vec![config::Peer {
+            name: String::from("laptop"),
+            address: Some(Ipv4Addr::new(10, 10, 10, 253)),
+            ..Default::default()
+        }];
+        let mut leases = Leases::new(&peers);
+        assert_eq!(leases.acquire(None), Some(252));
+        assert_eq!(leases.acquire(Some(&peers[0])), Some(253));
+        leases.release(253);
+        assert_eq!(leases.acquire(None), Some(251));
+        leases.release(251);
+        assert_eq!(leases.acquire(None), Some(251));
+
+        leases.update(&[], &[251, 252]);
+        assert_eq!(leases.acquire(None), Some(253));
+        assert_eq!(leases.acquire(None), Some(250));
+    }
+
+    #[test]
+    fn session_matches_test() {
+        let addr = "192.0.2.1:40000".parse().unwrap();
+        let session = Session::new(
+            1,
+            addr,
+            String::from("laptop"),
+            String::from("laptop"),
+            Default::default(),
+            Default::default(),
+        );
+        assert!(session.matches(5, "5"));
+        assert!(session.matches(5, "10.10.10.5"));
+        assert!(session.matches(5, "192.0.2.1"));
+        assert!(session.matches(5, "192.0.2.1:40000"));
+        assert!(session.matches(5, "laptop"));
+        assert!(!session.matches(5, "phone"));
+        let anonymous = Session::new(
+            1,
+            addr,
+            String::new(),
+            String::new(),
+            Default::default(),
+            Default::default(),
+        );
+        assert!(!anonymous.matches(5, ""));
+    }
+
+    #[test]
+    fn peer_quota_test() {
+        let quota = quota::Quota {
+            daily: Some(100),
+            monthly: Some(1000),
+            action: quota::Action::Disconnect,
+        };
+        let peer = config::Peer {
+            name: String::from("laptop"),
+            quota_daily: Some(quota::Bytes(200)),
+            ..Default::default()
+        };
+        assert_eq!(peer_quota(quota, None), quota);
+        let addr = "192.0.2.1:40000".parse().unwrap();
+        assert_eq!(identity(Some(&peer), addr), "laptop");
+        assert_eq!(identity(None, addr), "192.0.2.1");
+        let quota = peer_quota(quota, Some(&peer));
+        assert_eq!((quota.daily, quota.monthly), (Some(200), Some(1000)));
+
+        let rate = ratelimit::Limits {
+            up: Some(100),
+            down: None,
+        };
+        let peer = config::Peer {
+            rate_down: Some(quota::Bytes(300)),
+            ..peer
+        };
+        assert_eq!(
+            peer_rate(rate, Some(&peer)),
+            ratelimit::Limits {
+                up: Some(100),
+                down: Some(300),
+            }
+        );
+    }
+
+    #[test]
+    fn bans_test() {
+        let mut bans = Bans::default();
+        let addr: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        bans.add("laptop");
+        bans.add("192.0.2.1");
+        assert!(bans.contains("laptop", other));
+        assert!(bans.contains("", addr));
+        assert!(!bans.contains("", other));
+        assert!(bans.remove("laptop"));
+        assert!(!bans.remove("laptop"));
+        assert!(!bans.contains("laptop", other));
+    }
+
+    #[test]
+    fn subnets_test() {
+        let peer = config::Peer {
+            name: String::from("branch"),
+            subnets: Some(vec!["192.168.0.0/16".parse().unwrap()]),
+            ..Default::default()
+        };
+        let lan: utils::Ipv4Net = "192.168.5.0/24".parse().unwrap();
+        check_subnets(Some(&peer), &[lan]).unwrap();
+        check_subnets(None, &[]).unwrap();
+        assert!(check_subnets(None, &[lan]).is_err());
+        assert!(check_subnets(Some(&peer), &["10.0.0.0/8".parse().unwrap()]).is_err());
+
+        let addr = "192.0.2.1:40000".parse().unwrap();
+        let mut session = Session::new(
+            1,
+            addr,
+            String::from("branch"),
+            String::from("branch"),
+            Default::default(),
+            Default::default(),
+        );
+        session.subnets = vec![lan];
+        let mut client_info = TransientHashMap::new(60);
+        client_info.insert(5, session);
+        let mut packet = [0u8; 20];
+        packet[16..20].copy_from_slice(&[192, 168, 5, 9]);
+        assert_eq!(lookup(&client_info, &packet), Some(5));
+        packet[16..20].copy_from_slice(&[10, 10, 10, 7]);
+        assert_eq!(lookup(&client_info, &packet), Some(7));
+        packet[16..20].copy_from_slice(&[192, 168, 6, 9]);
+        assert_eq!(lookup(&client_info, &packet), None);
+
+        let session = client_info.get(&5).unwrap();
+        packet[0] = 0x45;
+        packet[12..16].copy_from_slice(&[10, 10, 10, 5]);
+        assert!(session.sends_from(5, &packet));
+        assert!(!session.sends_from(6, &packet));
+        packet[12..16].copy_from_slice(&[192, 168, 5, 1]);
+        assert!(session.sends_from(5, &packet));
+        packet[12..16].copy_from_slice(&[10, 10, 10, 7]);
+        assert!(!session.sends_from(5, &packet));
+        packet[0] = 0x60;
+        packet[12..16].copy_from_slice(&[10, 10, 10, 5]);
+        assert!(!session.sends_from(5, &packet));
+
+        let inner = ["192.168.5.128/25".parse().unwrap()];
+        assert_eq!(replaced_sessions(&client_info, &inner, "branch"), Ok(vec![5]));
+        assert!(replaced_sessions(&client_info, &inner, "other").is_err());
+        assert_eq!(replaced_sessions(&client_info, &[], "other"), Ok(vec![]));
+    }
+
+    #[test]
+    fn switch_frame_test() {
+        let addr = "192.0.2.1:40000".parse().unwrap();
+        let mut client_info = TransientHashMap::new(60);
+        for id in 2..5 {
+            let (name, identity) = (String::new(), identity(None, addr));
+            let session =
+                Session::new(1, addr, name, identity, Default::default(), Default::default());
+            client_info.insert(id, session);
+        }
+        let frame = |dst: u8, src: u8| {
+            let mut frame = vec![0x02, 0, 0, 0, 0, dst, 0x02, 0, 0, 0, 0, src, 0x08, 0x06];
+            if dst == 0xff {
+                frame[0..6].copy_from_slice(&[0xff; 6]);
+            }
+            frame
+        };
+        let allow = acl::Policy::Allow;
+        let mut metrics = metrics::Metrics::default();
+        let mut switch = |from, frame: &[u8], policy| {
+            let (local, mut sessions) =
+                switch_frame(&mut client_info, from, frame, policy, &mut metrics);
+            sessions.sort();
+            (local, sessions)
+        };
+
+        // Broadcasts and unknown addresses are flooded.
+        assert_eq!(switch(Some(2), &frame(0xff, 2), &allow), (true, vec![3, 4]));
+        assert_eq!(switch(None, &frame(9, 1), &allow), (false, vec![2, 3, 4]));
+        // Learned addresses are switched to their session.
+        assert_eq!(switch(None, &frame(2, 1), &allow), (false, vec![2]));
+        assert_eq!(switch(Some(3), &frame(2, 3), &allow), (false, vec![2]));
+        assert_eq!(switch(Some(2), &frame(2, 2), &allow), (false, vec![]));
+        // Addresses move to the session that sent them last.
+        assert_eq!(switch(Some(4), &frame(1, 2), &allow), (true, vec![2, 3]));
+        assert_eq!(switch(None, &frame(2, 1), &allow), (false, vec![4]));
+        // Clients only reach the TAP device if isolated.
+        let deny = acl::Policy::Deny;
+        assert_eq!(switch(Some(3), &frame(2, 3), &deny), (false, vec![]));
+        assert_eq!(switch(Some(3), &frame(0xff, 3), &deny), (true, vec![]));
+        assert_eq!(switch(None, &frame(3, 1), &deny), (false, vec![3]));
+        assert_eq!(switch(None, &frame(3, 1)[..13], &allow), (false, vec![]));
+        assert_eq!(metrics.client_to_client_denied, 1);
+    }
+
+    #[test]
+    fn datagram_len_test() {
+        let key = derive_keys(Zeroizing::new(String::from("password")));
+        let mut encoder = snap::raw::Encoder::new();
+        for &tap in &[false, true] {
+            let len = datagram_len(device::MAX_MTU, tap);
+            let mut packet = vec![0u8; len - DATA_OVERHEAD as usize];
+            thread_rng().fill(&mut packet[..]);
+            let msg = Message::Data {
+                id: 2,
+                token: 1,
+                data: encoder.compress_vec(&packet).unwrap(),
+            };
+            // Sealed like `send_data` does.
+            let sealed_len = serialize(&msg).unwrap().len() + 2 * key.algorithm().tag_len();
+            assert!(sealed_len <= len);
+            assert_eq!(seal_probe(&key, 2, 1, 1, len).len(), len);
+        }
+    }
+
+    #[test]
+    fn misplaced_sessions_test() {
+        let addr = "192.0.2.1:40000".parse().unwrap();
+        let mut client_info = TransientHashMap::new(60);
+        for &(id, name) in &[(100, "laptop"), (101, "phone"), (2, "desktop")] {
+            let (name, identity) = (String::from(name), String::from(name));
+            let session =
+                Session::new(1, addr, name, identity, Default::default(), Default::default());
+            client_info.insert(id, session);
+        }
+        let peer = |name: &str, address: Option<&str>| config::Peer {
+            name: String::from(name),
+            address: address.map(|address| address.parse().unwrap()),
+            ..Default::default()
+        };
+        let mut peers = vec![
+            peer("laptop", Some("10.10.10.100")),
+            peer("phone", None),
+            peer("desktop", None),
+        ];
+        assert!(misplaced_sessions(&client_info, &peers).is_empty());
+        // The laptop moves to the phone's address, which closes both sessions.
+        peers[0].address = Some("10.10.10.101".parse().unwrap());
+        peers[2].address = Some("10.10.10.2".parse().unwrap());
+        let mut misplaced = misplaced_sessions(&client_info, &peers);
+        misplaced.sort();
+        assert_eq!(misplaced, vec![100, 101]);
+    }
+
+    #[test]
+    fn is_authorized_test() {
+        let peers = vec![config::Peer {
+            name: String::from("laptop"),
+            address: None,
+            ..Default::default()
+        }];
+        assert!(is_authorized(&[], "anyone"));
+        assert!(is_authorized(&peers, "laptop"));
+        assert!(!is_authorized(&peers, "phone"));
+    }
+
     #[test]
     #[cfg(target_os = "linux")]
     fn integration_test() {
         assert!(utils::is_root());
-        let _server =
-            thread::spawn(move || serve(8964, "password", "8.8.8.8".parse::<IpAddr>().unwrap()));
+        let server = cli::Server {
+            bind_addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 8964,
+            key: Zeroizing::new(String::from("password")),
+            dns: dns::Settings {
+                servers: vec!["8.8.8.8".parse::<IpAddr>().unwrap()],
+                ..Default::default()
+            },
+            routing: utils::Routing {
+                routes: vec!["198.51.100.0/24".parse().unwrap()],
+                default_route: Some(false),
+            },
+            public_addr: None,
+            sandbox: Default::default(),
+            peers: Vec::new(),
+            control: std::env::temp_dir()
+                .join("kytan-integration.sock")
+                .to_string_lossy()
+                .into_owned(),
+            metrics: None,
+            quota: Default::default(),
+            quota_state: std::env::temp_dir()
+                .join("kytan-integration.json")
+                .to_string_lossy()
+                .into_owned(),
+            rate: Default::default(),
+            on_connect: None,
+            on_disconnect: None,
+            client_to_client: Default::default(),
+            tap: false,
+            interface: Some(String::from("kytan%d")),
+            mtu: 1280,
+        };
+        let _server = thread::spawn(move || serve(server));
 
         thread::sleep(time::Duration::from_secs(1));
         assert!(LISTENING.load(Ordering::Relaxed));
@@ -452,10 +2115,43 @@ mod tests {
         let local_addr: SocketAddr = "0.0.0.0:0".parse::<SocketAddr>().unwrap();
         let local_socket = UdpSocket::bind(&local_addr).unwrap();
 
-        let (id, _, _) = initiate(&local_socket, &remote_addr, "password").unwrap();
+        let key = derive_keys(Zeroizing::new(String::from("password")));
+        let (id, token, _, routing, mtu) =
+            initiate(&local_socket, &remote_addr, &key, "", &[], false, 1500).unwrap();
         assert_eq!(id, 253);
+        assert_eq!(mtu, 1280);
+        assert_eq!(routing.default_route, Some(false));
+
+        let probe = seal_probe(&key, id, token, 7, datagram_len(mtu, false));
+        local_socket.send_to(&probe, remote_addr).unwrap();
+        let mut buf = [0u8; 1600];
+        let (len, _) = local_socket.recv_from(&mut buf).unwrap();
+        let mut metrics = metrics::Metrics::default();
+        let ack = open(&key, &mut buf[..len], &mut metrics);
+        assert_eq!(ack, Some(Message::ProbeAck { token, seq: 7 }));
 
-        let _client = thread::spawn(move || connect("127.0.0.1", 8964, false, "password"));
+        let client = cli::Client {
+            remote_addr: String::from("127.0.0.1"),
+            port: 8964,
+            key: Zeroizing::new(String::from("password")),
+            name: String::new(),
+            default_route: false,
+            routes: Vec::new(),
+            excludes: Vec::new(),
+            subnets: Vec::new(),
+            fwmark: None,
+            kill_switch: false,
+            tap: false,
+            interface: None,
+            mtu: device::DEFAULT_MTU,
+            pmtu_discovery: true,
+            dns_backend: dns::Backend::File,
+            sandbox: Default::default(),
+            metrics: None,
+            up: None,
+            down: None,
+        };
+        let _client = thread::spawn(move || connect(client));
 
         thread::sleep(time::Duration::from_secs(1));
         assert!(CONNECTED.load(Ordering::Relaxed));
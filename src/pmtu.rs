@@ -0,0 +1,178 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::{Duration, Instant};
+
+/// How long to wait for the acknowledgement of a probe.
+const TIMEOUT: Duration = Duration::from_secs(1);
+/// Probes of a size are sent this often before it is considered too big.
+const ATTEMPTS: u32 = 3;
+/// How often the path is probed again, e.g. after roaming to another network.
+const INTERVAL: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Copy)]
+struct Probe {
+    seq: u32,
+    mtu: u32,
+    sent: Instant,
+    attempts: u32,
+}
+
+/// Path MTU discovery: a binary search for the largest MTU between `min` and
+/// `max` whose packets reach the other end, repeated every `INTERVAL`.
+#[derive(Debug)]
+pub struct Discovery {
+    min: u32,
+    max: u32,
+    /// Packets of the current search of `low` bytes got through, and of
+    /// `high` bytes did not.
+    low: u32,
+    high: u32,
+    searching: bool,
+    probe: Option<Probe>,
+    seq: u32,
+    next_search: Instant,
+    result: Option<u32>,
+}
+
+impl Discovery {
+    pub fn new(min: u32, max: u32, now: Instant) -> Discovery {
+        Discovery {
+            min,
+            max,
+            low: min,
+            high: max + 1,
+            searching: false,
+            probe: None,
+            seq: 0,
+            next_search: now,
+            result: None,
+        }
+    }
+
+    /// The probe to send at `now`, if any, as its sequence number and MTU.
+    pub fn probe(&mut self, now: Instant) -> Option<(u32, u32)> {
+        if let Some(ref mut probe) = self.probe {
+            if now < probe.sent + TIMEOUT {
+                return None;
+            }
+            if probe.attempts < ATTEMPTS {
+                probe.attempts += 1;
+                probe.sent = now;
+                return Some((probe.seq, probe.mtu));
+            }
+            // Every attempt was lost, so the probe was too big.
+            self.high = probe.mtu;
+            self.probe = None;
+        } else if !self.searching {
+            if now < self.next_search {
+                return None;
+            }
+            self.searching = true;
+            self.low = self.min;
+            self.high = self.max + 1;
+            // Most paths carry the largest size, which settles it right away.
+            return Some(self.send(self.max, now));
+        }
+        if self.high - self.low <= 1 {
+            self.searching = false;
+            self.next_search = now + INTERVAL;
+            self.result = Some(self.low);
+            return None;
+        }
+        Some(self.send((self.low + self.high) / 2, now))
+    }
+
+    fn send(&mut self, mtu: u32, now: Instant) -> (u32, u32) {
+        self.seq = self.seq.wrapping_add(1);
+        self.probe = Some(Probe {
+            seq: self.seq,
+            mtu,
+            sent: now,
+            attempts: 1,
+        });
+        (self.seq, mtu)
+    }
+
+    /// Records that probe `seq` got through.
+    pub fn ack(&mut self, seq: u32) {
+        if let Some(probe) = self.probe {
+            if probe.seq == seq {
+                self.low = probe.mtu;
+                self.probe = None;
+            }
+        }
+    }
+
+    /// When `probe` has to be called again.
+    pub fn deadline(&self) -> Instant {
+        match self.probe {
+            Some(probe) => probe.sent + TIMEOUT,
+            None => self.next_search,
+        }
+    }
+
+    /// The MTU found by a search that just finished.
+    pub fn result(&mut self) -> Option<u32> {
+        self.result.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pmtu::*;
+
+    /// Runs a search over a path that carries up to `path` bytes.
+    fn search(discovery: &mut Discovery, path: u32, mut now: Instant) -> (u32, usize) {
+        let mut probes = 0;
+        loop {
+            if let Some((seq, mtu)) = discovery.probe(now) {
+                probes += 1;
+                if mtu <= path {
+                    discovery.ack(seq);
+                }
+                continue;
+            }
+            if let Some(mtu) = discovery.result() {
+                return (mtu, probes);
+            }
+            now = discovery.deadline();
+        }
+    }
+
+    #[test]
+    fn discovery_test() {
+        let now = Instant::now();
+        let mut discovery = Discovery::new(576, 1500, now);
+        assert_eq!(search(&mut discovery, 1500, now), (1500, 1));
+        assert_eq!(discovery.probe(now), None);
+        assert_eq!(discovery.deadline(), now + INTERVAL);
+
+        let now = discovery.deadline();
+        let (mtu, probes) = search(&mut discovery, 1412, now);
+        assert_eq!(mtu, 1412);
+        assert!(probes < 40);
+
+        let mut discovery = Discovery::new(576, 1500, now);
+        assert_eq!(search(&mut discovery, 0, now).0, 576);
+
+        // A lost probe is sent again before giving up on its size.
+        let mut discovery = Discovery::new(576, 1500, now);
+        let (seq, mtu) = discovery.probe(now).unwrap();
+        assert_eq!(discovery.probe(now + TIMEOUT), Some((seq, mtu)));
+        discovery.ack(seq);
+        assert_eq!(discovery.probe(now + TIMEOUT), None);
+        assert_eq!(discovery.result(), Some(1500));
+    }
+}
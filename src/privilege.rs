@@ -0,0 +1,426 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::utils;
+use log::{info, warn};
+use std::ffi::CString;
+#[cfg(target_os = "linux")]
+use std::fs;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::{env, io, panic, process};
+
+#[cfg(target_os = "linux")]
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x20080522;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const AUDIT_ARCH: u32 = 0xc000003e;
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+const AUDIT_ARCH: u32 = 0xc00000b7;
+/// Set in the numbers of x32 system calls, which share the x86_64 arch.
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+// Syscalls kytan and the commands it spawns never need once the tunnel is up.
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_ptrace,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_kexec_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_reboot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_userfaultfd,
+];
+
+/// The sandboxed child of a client, which SIGINT and SIGTERM are passed on to.
+pub static CHILD: AtomicI32 = AtomicI32::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capability {
+    NetBindService = 10,
+    NetAdmin = 12,
+}
+
+impl Capability {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::NetBindService => "CAP_NET_BIND_SERVICE",
+            Capability::NetAdmin => "CAP_NET_ADMIN",
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn mask(&self) -> u64 {
+        1 << (*self as u64)
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+#[cfg(target_os = "linux")]
+fn parse_cap_mask(status: &str, field: &str) -> Option<u64> {
+    let prefix = format!("{}:", field);
+    status
+        .lines()
+        .find(|line| line.starts_with(&prefix))
+        .and_then(|line| u64::from_str_radix(line[prefix.len()..].trim(), 16).ok())
+}
+
+/// Checks that the process holds `caps` and makes them available to the
+/// helper commands (`ip`, `route`, `ifconfig`, ...) it spawns.
+#[cfg(target_os = "linux")]
+pub fn acquire(caps: &[Capability]) -> Result<(), String> {
+    if utils::is_root() {
+        return Ok(());
+    }
+    let status = fs::read_to_string("/proc/self/status").map_err(|e| e.to_string())?;
+    let effective = parse_cap_mask(&status, "CapEff").ok_or("Unable to read CapEff")?;
+    let missing: Vec<&str> = caps
+        .iter()
+        .filter(|cap| effective & cap.mask() == 0)
+        .map(|cap| cap.name())
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!(
+            "Missing {}. Run as root or grant it with `setcap {}+ep` or ambient capabilities.",
+            missing.join(", "),
+            missing
+                .iter()
+                .map(|name| name.to_lowercase())
+                .collect::<Vec<String>>()
+                .join(",")
+        ));
+    }
+    for cap in caps {
+        if let Err(e) = raise_ambient(*cap) {
+            warn!("Unable to pass {} on to child processes: {}", cap.name(), e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+pub fn acquire(_caps: &[Capability]) -> Result<(), String> {
+    if utils::is_root() {
+        Ok(())
+    } else {
+        Err(String::from("Please run as root"))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn raise_ambient(cap: Capability) -> Result<(), String> {
+    // A capability must be inheritable before it can be made ambient.
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    if unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) } != 0 {
+        return Err(format!("capget: {}", io::Error::last_os_error()));
+    }
+    data[0].inheritable |= cap.mask() as u32;
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) } != 0 {
+        return Err(format!("capset: {}", io::Error::last_os_error()));
+    }
+    let res = unsafe {
+        libc::prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_RAISE,
+            cap as libc::c_ulong,
+            0,
+            0,
+        )
+    };
+    if res != 0 {
+        return Err(format!("prctl: {}", io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Restrictions applied once the TUN device and socket are open.
+#[derive(Debug, Clone, Default)]
+pub struct Sandbox {
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub chroot: Option<String>,
+    pub seccomp: bool,
+}
+
+impl Sandbox {
+    pub fn is_empty(&self) -> bool {
+        self.user.is_none() && self.group.is_none() && self.chroot.is_none() && !self.seccomp
+    }
+
+    /// Switches to the configured user and group, chroots and installs the
+    /// seccomp filter. `keep` stays effective (and ambient, for the commands
+    /// spawned on teardown) after leaving root.
+    pub fn apply(&self, keep: &[Capability]) -> Result<(), String> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        // Look names up before chroot hides /etc/passwd and /etc/group.
+        let uid = match self.user {
+            Some(ref user) => Some(lookup_user(user)?),
+            None => None,
+        };
+        let gid = match (&self.group, uid) {
+            (Some(group), _) => Some(lookup_group(group)?),
+            (None, Some((_, gid))) => Some(gid),
+            (None, None) => None,
+        };
+
+        #[cfg(target_os = "linux")]
+        check(unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) }, "prctl")?;
+
+        if let Some(ref dir) = self.chroot {
+            info!("Changing root directory to {}.", dir);
+            let path = CString::new(dir.as_str()).map_err(|e| e.to_string())?;
+            check(unsafe { libc::chroot(path.as_ptr()) }, "chroot")?;
+            env::set_current_dir("/").map_err(|e| format!("chdir: {}", e))?;
+        }
+        if let Some(gid) = gid {
+            info!("Switching to group {}.", gid);
+            check(unsafe { libc::setgroups(1, &gid) }, "setgroups")?;
+            check(unsafe { libc::setgid(gid) }, "setgid")?;
+        }
+        if let Some((uid, _)) = uid {
+            info!("Switching to user {}.", uid);
+            check(unsafe { libc::setuid(uid) }, "setuid")?;
+            keep_capabilities(keep)?;
+        }
+        if self.seccomp {
+            install_seccomp_filter()?;
+        }
+        Ok(())
+    }
+
+    /// Applies the sandbox in a child process, so that the parent keeps the
+    /// privileges, users and commands it needs to tear the tunnel down.
+    /// Returns `true` in the child, and `false` in the parent once the child
+    /// has exited.
+    pub fn fork(&self, keep: &[Capability]) -> Result<bool, String> {
+        match unsafe { libc::fork() } {
+            -1 => Err(format!("fork: {}", io::Error::last_os_error())),
+            0 => {
+                // Leave the teardown to the parent, even after a panic.
+                let hook = panic::take_hook();
+                panic::set_hook(Box::new(move |info| {
+                    hook(info);
+                    process::exit(101);
+                }));
+                self.apply(keep)?;
+                Ok(true)
+            }
+            child => {
+                CHILD.store(child, Ordering::Relaxed);
+                let mut status = 0;
+                while unsafe { libc::waitpid(child, &mut status, 0) } == -1 {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        return Err(format!("waitpid: {}", e));
+                    }
+                }
+                CHILD.store(0, Ordering::Relaxed);
+                Ok(false)
+            }
+        }
+    }
+}
+
+fn check(res: libc::c_int, name: &str) -> Result<(), String> {
+    if res != 0 {
+        Err(format!("{}: {}", name, io::Error::last_os_error()))
+    } else {
+        Ok(())
+    }
+}
+
+fn lookup_user(name: &str) -> Result<(libc::uid_t, libc::gid_t), String> {
+    let cname = CString::new(name).map_err(|e| e.to_string())?;
+    let pw = unsafe { libc::getpwnam(cname.as_ptr()) };
+    if pw.is_null() {
+        return Err(format!("Unknown user {}", name));
+    }
+    Ok(unsafe { ((*pw).pw_uid, (*pw).pw_gid) })
+}
+
+fn lookup_group(name: &str) -> Result<libc::gid_t, String> {
+    let cname = CString::new(name).map_err(|e| e.to_string())?;
+    let gr = unsafe { libc::getgrnam(cname.as_ptr()) };
+    if gr.is_null() {
+        return Err(format!("Unknown group {}", name));
+    }
+    Ok(unsafe { (*gr).gr_gid })
+}
+
+#[cfg(target_os = "macos")]
+fn keep_capabilities(_keep: &[Capability]) -> Result<(), String> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn keep_capabilities(keep: &[Capability]) -> Result<(), String> {
+    let mask = keep.iter().fold(0, |mask, cap| mask | cap.mask()) as u32;
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = [
+        CapData {
+            effective: mask,
+            permitted: mask,
+            inheritable: mask,
+        },
+        CapData::default(),
+    ];
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) } != 0 {
+        return Err(format!("capset: {}", io::Error::last_os_error()));
+    }
+    for cap in keep {
+        raise_ambient(*cap)?;
+    }
+    Ok(())
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn seccomp_filter() -> Vec<libc::sock_filter> {
+    fn stmt(code: u32, k: u32) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+    fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    let deny = libc::SECCOMP_RET_ERRNO | (libc::EPERM as u32);
+    let mut filter = vec![
+        // offsetof(struct seccomp_data, arch)
+        stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, 4),
+        jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, AUDIT_ARCH, 1, 0),
+        stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        // offsetof(struct seccomp_data, nr)
+        stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, 0),
+        // x32 system calls would bypass the numbers below.
+        jump(libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K, X32_SYSCALL_BIT, 0, 1),
+        stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+    ];
+    for nr in DENIED_SYSCALLS {
+        filter.push(jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, *nr as u32, 0, 1));
+        filter.push(stmt(libc::BPF_RET | libc::BPF_K, deny));
+    }
+    filter.push(stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW));
+    filter
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn install_seccomp_filter() -> Result<(), String> {
+    info!("Installing seccomp filter.");
+    let filter = seccomp_filter();
+    let prog = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_ptr() as *mut libc::sock_filter,
+    };
+    check(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) }, "prctl")?;
+    check(
+        unsafe {
+            libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER,
+                &prog as *const libc::sock_fprog,
+            )
+        },
+        "seccomp",
+    )
+}
+
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+fn install_seccomp_filter() -> Result<(), String> {
+    warn!("seccomp is not supported on this platform.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::privilege::*;
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn parse_cap_mask_test() {
+        let status = "Name:\tkytan\nCapInh:\t0000000000000000\nCapEff:\t0000000000001400\n";
+        let mask = parse_cap_mask(status, "CapEff").unwrap();
+        assert_eq!(mask, 0x1400);
+        assert!(mask & Capability::NetAdmin.mask() != 0);
+        assert!(mask & Capability::NetBindService.mask() != 0);
+        assert_eq!(parse_cap_mask(status, "CapInh"), Some(0));
+        assert_eq!(parse_cap_mask(status, "CapAmb"), None);
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    fn seccomp_filter_test() {
+        let filter = seccomp_filter();
+        // Arch check, syscall load, x32 check, a jump and return per syscall,
+        // default allow.
+        assert_eq!(filter.len(), 4 + 2 + 2 * DENIED_SYSCALLS.len() + 1);
+        assert_eq!(filter[4].k, X32_SYSCALL_BIT);
+        assert_eq!(filter.last().unwrap().k, libc::SECCOMP_RET_ALLOW);
+    }
+
+    #[test]
+    fn sandbox_test() {
+        assert!(Sandbox::default().is_empty());
+        Sandbox::default().apply(&[Capability::NetAdmin]).unwrap();
+        assert!(lookup_user("root").is_ok());
+        assert!(lookup_user("kytan-no-such-user").is_err());
+    }
+}
@@ -0,0 +1,373 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::control;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::str::FromStr;
+
+const SECONDS_PER_DAY: u64 = 86400;
+pub const DEFAULT_THROTTLE: u64 = 128 * 1024;
+pub const DEFAULT_STATE: &str = "/var/lib/kytan/usage.json";
+
+/// A byte count such as `500M` or `10G`, in powers of 1024.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Bytes(pub u64);
+
+impl FromStr for Bytes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Bytes, String> {
+        let (digits, shift) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+            Some('K') => (&s[..s.len() - 1], 10),
+            Some('M') => (&s[..s.len() - 1], 20),
+            Some('G') => (&s[..s.len() - 1], 30),
+            Some('T') => (&s[..s.len() - 1], 40),
+            _ => (s, 0),
+        };
+        digits
+            .parse::<u64>()
+            .ok()
+            .and_then(|n| n.checked_mul(1 << shift))
+            .map(Bytes)
+            .ok_or_else(|| format!("invalid byte count: {}", s))
+    }
+}
+
+impl TryFrom<String> for Bytes {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Bytes, String> {
+        s.parse()
+    }
+}
+
+/// What happens once a client has used up its quota.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Action {
+    #[default]
+    Disconnect,
+    /// Forward at most this many bytes per second.
+    Throttle(u64),
+}
+
+/// Traffic allowed per client identity, counting both directions.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Quota {
+    pub daily: Option<u64>,
+    pub monthly: Option<u64>,
+    pub action: Action,
+}
+
+pub enum Verdict {
+    Forward,
+    Drop,
+    Disconnect(String),
+}
+
+/// Days since the Unix epoch to months since year 0, using the proleptic
+/// Gregorian calendar in UTC.
+fn month(day: u64) -> u64 {
+    let z = day as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y * 12 + m - 1) as u64
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Usage {
+    bytes_in: u64,
+    bytes_out: u64,
+    day: u64,
+    daily: u64,
+    month: u64,
+    monthly: u64,
+    #[serde(skip)]
+    second: u64,
+    #[serde(skip)]
+    second_bytes: u64,
+}
+
+impl Usage {
+    fn daily(&self, now: u64) -> u64 {
+        if self.day == now / SECONDS_PER_DAY {
+            self.daily
+        } else {
+            0
+        }
+    }
+
+    fn monthly(&self, now: u64) -> u64 {
+        if self.month == month(now / SECONDS_PER_DAY) {
+            self.monthly
+        } else {
+            0
+        }
+    }
+
+    fn exceeded(&self, quota: &Quota, now: u64) -> Option<String> {
+        match (quota.daily, quota.monthly) {
+            (Some(daily), _) if self.daily(now) >= daily => {
+                Some(format!("daily quota of {} bytes exceeded", daily))
+            }
+            (_, Some(monthly)) if self.monthly(now) >= monthly => {
+                Some(format!("monthly quota of {} bytes exceeded", monthly))
+            }
+            _ => None,
+        }
+    }
+
+    fn add(&mut self, bytes: u64, inbound: bool, now: u64) {
+        let (daily, monthly) = (self.daily(now), self.monthly(now));
+        self.day = now / SECONDS_PER_DAY;
+        self.month = month(self.day);
+        self.daily = daily + bytes;
+        self.monthly = monthly + bytes;
+        if inbound {
+            self.bytes_in += bytes;
+        } else {
+            self.bytes_out += bytes;
+        }
+    }
+}
+
+/// Traffic per client identity, kept across reconnects and, in the state
+/// file, across restarts.
+#[derive(Default)]
+pub struct Accounting {
+    usage: HashMap<String, Usage>,
+    state: Option<File>,
+}
+
+impl Accounting {
+    /// Loads the traffic saved in `path`, which is created if missing. It
+    /// stays open so that it can be saved to after dropping privileges.
+    pub fn open(path: &Path) -> Result<Accounting, String> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut text = String::new();
+        file.read_to_string(&mut text).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let usage = if text.is_empty() {
+            HashMap::new()
+        } else {
+            serde_json::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))?
+        };
+        Ok(Accounting {
+            usage,
+            state: Some(file),
+        })
+    }
+
+    /// Writes the traffic to the state file, if there is one.
+    pub fn save(&mut self) -> Result<(), String> {
+        let file = match self.state {
+            Some(ref mut file) => file,
+            None => return Ok(()),
+        };
+        let text = serde_json::to_vec(&self.usage).unwrap();
+        file.seek(SeekFrom::Start(0))
+            .and_then(|_| file.set_len(0))
+            .and_then(|_| file.write_all(&text))
+            .map_err(|e| e.to_string())
+    }
+
+    /// Returns why `identity` may not use the tunnel right now, if it may not.
+    pub fn exceeded(&self, identity: &str, quota: &Quota, now: u64) -> Option<String> {
+        self.usage
+            .get(identity)
+            .and_then(|usage| usage.exceeded(quota, now))
+    }
+
+    /// Accounts for a packet of `bytes`, unless the quota says to drop it.
+    /// `now` is in seconds since the Unix epoch.
+    pub fn record(
+        &mut self,
+        identity: &str,
+        quota: &Quota,
+        bytes: u64,
+        inbound: bool,
+        now: u64,
+    ) -> Verdict {
+        if !self.usage.contains_key(identity) {
+            self.usage.insert(String::from(identity), Usage::default());
+        }
+        let usage = self.usage.get_mut(identity).unwrap();
+        if let Some(reason) = usage.exceeded(quota, now) {
+            match quota.action {
+                Action::Disconnect => return Verdict::Disconnect(reason),
+                Action::Throttle(rate) => {
+                    if usage.second != now {
+                        usage.second = now;
+                        usage.second_bytes = 0;
+                    }
+                    if usage.second_bytes + bytes > rate {
+                        return Verdict::Drop;
+                    }
+                    usage.second_bytes += bytes;
+                }
+            }
+        }
+        usage.add(bytes, inbound, now);
+        Verdict::Forward
+    }
+
+    /// Forgets identities without traffic this month, which no quota counts.
+    pub fn prune(&mut self, now: u64) {
+        let month = month(now / SECONDS_PER_DAY);
+        self.usage.retain(|_, usage| usage.month == month);
+    }
+
+    pub fn info(&self, now: u64) -> Vec<control::UsageInfo> {
+        self.usage
+            .iter()
+            .map(|(identity, usage)| control::UsageInfo {
+                identity: identity.clone(),
+                bytes_in: usage.bytes_in,
+                bytes_out: usage.bytes_out,
+                today: usage.daily(now),
+                this_month: usage.monthly(now),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::quota::*;
+
+    #[test]
+    fn bytes_test() {
+        assert_eq!("1024".parse::<Bytes>().unwrap(), Bytes(1024));
+        assert_eq!("500M".parse::<Bytes>().unwrap(), Bytes(500 << 20));
+        assert_eq!("10g".parse::<Bytes>().unwrap(), Bytes(10 << 30));
+        assert!("G".parse::<Bytes>().is_err());
+        assert!("1.5G".parse::<Bytes>().is_err());
+        assert!("99999999999T".parse::<Bytes>().is_err());
+    }
+
+    #[test]
+    fn month_test() {
+        assert_eq!(month(0), 1970 * 12);
+        assert_eq!(month(30), 1970 * 12);
+        assert_eq!(month(31), 1970 * 12 + 1);
+        // 2024-02-29 and 2024-03-01
+        assert_eq!(month(19782), 2024 * 12 + 1);
+        assert_eq!(month(19783), 2024 * 12 + 2);
+    }
+
+    #[test]
+    fn disconnect_test() {
+        let quota = Quota {
+            daily: Some(100),
+            monthly: None,
+            action: Action::Disconnect,
+        };
+        let mut accounting = Accounting::default();
+        let now = 19770 * SECONDS_PER_DAY;
+        assert!(matches!(
+            accounting.record("laptop", &quota, 60, true, now),
+            Verdict::Forward
+        ));
+        assert!(matches!(
+            accounting.record("laptop", &quota, 60, false, now),
+            Verdict::Forward
+        ));
+        assert!(accounting.exceeded("laptop", &quota, now).is_some());
+        assert!(matches!(
+            accounting.record("laptop", &quota, 1, true, now),
+            Verdict::Disconnect(_)
+        ));
+        assert!(accounting.exceeded("phone", &quota, now).is_none());
+        // A new day starts with a fresh quota but keeps the totals.
+        let tomorrow = now + SECONDS_PER_DAY;
+        assert!(accounting.exceeded("laptop", &quota, tomorrow).is_none());
+        let info = accounting.info(tomorrow);
+        assert_eq!((info[0].bytes_in, info[0].bytes_out, info[0].today), (60, 60, 0));
+        assert_eq!(info[0].this_month, 120);
+    }
+
+    #[test]
+    fn prune_test() {
+        let quota = Quota::default();
+        let mut accounting = Accounting::default();
+        let now = 19770 * SECONDS_PER_DAY;
+        accounting.record("laptop", &quota, 60, true, now);
+        accounting.prune(now + SECONDS_PER_DAY);
+        assert_eq!(accounting.info(now).len(), 1);
+        accounting.prune(now + 31 * SECONDS_PER_DAY);
+        assert!(accounting.info(now).is_empty());
+    }
+
+    #[test]
+    fn throttle_test() {
+        let quota = Quota {
+            daily: None,
+            monthly: Some(10),
+            action: Action::Throttle(100),
+        };
+        let mut accounting = Accounting::default();
+        let now = 1000;
+        accounting.record("laptop", &quota, 10, true, now);
+        assert!(matches!(
+            accounting.record("laptop", &quota, 80, true, now),
+            Verdict::Forward
+        ));
+        assert!(matches!(
+            accounting.record("laptop", &quota, 80, true, now),
+            Verdict::Drop
+        ));
+        assert!(matches!(
+            accounting.record("laptop", &quota, 80, true, now + 1),
+            Verdict::Forward
+        ));
+    }
+
+    #[test]
+    fn state_test() {
+        let path = std::env::temp_dir().join(format!("kytan-usage-{}", std::process::id()));
+        let quota = Quota::default();
+        let now = 19770 * SECONDS_PER_DAY;
+        let mut accounting = Accounting::open(&path).unwrap();
+        accounting.record("laptop", &quota, 60, true, now);
+        accounting.save().unwrap();
+        accounting.record("laptop", &quota, 40, false, now);
+        accounting.save().unwrap();
+
+        let info = Accounting::open(&path).unwrap().info(now);
+        assert_eq!((info[0].bytes_in, info[0].bytes_out, info[0].today), (60, 40, 100));
+        fs::write(&path, "garbage").unwrap();
+        assert!(Accounting::open(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Instant;
+
+/// The bucket always holds at least one full packet, however low the rate.
+const MIN_BURST: u64 = 1600;
+
+/// Bytes per second a client may send (up) and receive (down).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Limits {
+    pub up: Option<u64>,
+    pub down: Option<u64>,
+}
+
+/// Allows `rate` bytes per second on average and bursts of up to one second.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: u64,
+    burst: u64,
+    tokens: u64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64, now: Instant) -> TokenBucket {
+        let burst = rate.max(MIN_BURST);
+        TokenBucket {
+            rate,
+            burst,
+            tokens: burst,
+            last: now,
+        }
+    }
+
+    /// Takes `bytes` out of the bucket, or returns false if there are not
+    /// enough left and the packet should be dropped.
+    pub fn take(&mut self, bytes: u64, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_nanos();
+        let refill = elapsed * u128::from(self.rate) / 1_000_000_000;
+        // Only move forward once a whole byte has accumulated, so that slow
+        // rates still refill when packets arrive faster than one per byte.
+        if refill > 0 {
+            self.tokens = (u128::from(self.tokens) + refill).min(u128::from(self.burst)) as u64;
+            self.last = now;
+        }
+        if self.tokens < bytes {
+            return false;
+        }
+        self.tokens -= bytes;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ratelimit::*;
+    use std::time::Duration;
+
+    #[test]
+    fn token_bucket_test() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10000, start);
+        assert!(bucket.take(6000, start));
+        assert!(bucket.take(4000, start));
+        assert!(!bucket.take(1, start));
+        let later = start + Duration::from_millis(100);
+        assert!(bucket.take(1000, later));
+        assert!(!bucket.take(1, later));
+        // Never more than the burst, however long the client was idle.
+        let idle = later + Duration::from_secs(60);
+        assert!(!bucket.take(10001, idle));
+        assert!(bucket.take(10000, idle));
+    }
+
+    #[test]
+    fn slow_rate_test() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, start);
+        assert!(bucket.take(MIN_BURST, start));
+        for i in 1..=1000 {
+            bucket.take(0, start + Duration::from_micros(500 * i));
+        }
+        assert!(bucket.take(500, start + Duration::from_millis(500)));
+    }
+}
@@ -0,0 +1,100 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub type Mac = [u8; 6];
+
+const HEADER_LEN: usize = 14;
+/// Addresses kept per session, so that a client cannot exhaust the server's
+/// memory by sending frames from random addresses.
+const MAX_MACS: usize = 256;
+
+pub fn destination(frame: &[u8]) -> Option<Mac> {
+    if frame.len() < HEADER_LEN {
+        return None;
+    }
+    let mut mac = [0u8; 6];
+    mac.copy_from_slice(&frame[0..6]);
+    Some(mac)
+}
+
+pub fn source(frame: &[u8]) -> Option<Mac> {
+    if frame.len() < HEADER_LEN {
+        return None;
+    }
+    let mut mac = [0u8; 6];
+    mac.copy_from_slice(&frame[6..12]);
+    Some(mac)
+}
+
+/// Broadcast and multicast addresses have the lowest bit of the first octet set.
+pub fn is_multicast(mac: &Mac) -> bool {
+    mac[0] & 1 == 1
+}
+
+/// The MAC addresses learned from the frames of one session, the most
+/// recently learned last.
+#[derive(Default, Debug)]
+pub struct Macs {
+    macs: Vec<Mac>,
+}
+
+impl Macs {
+    /// Returns whether `mac` is new to this session.
+    pub fn learn(&mut self, mac: Mac) -> bool {
+        if is_multicast(&mac) || self.contains(&mac) {
+            return false;
+        }
+        if self.macs.len() == MAX_MACS {
+            self.macs.remove(0);
+        }
+        self.macs.push(mac);
+        true
+    }
+
+    pub fn forget(&mut self, mac: &Mac) {
+        self.macs.retain(|m| m != mac);
+    }
+
+    pub fn contains(&self, mac: &Mac) -> bool {
+        self.macs.contains(mac)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::switch::*;
+
+    #[test]
+    fn macs_test() {
+        let mut frame = vec![0xff; 6];
+        frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 1]);
+        frame.extend_from_slice(&[0x08, 0x06]);
+        let src = source(&frame).unwrap();
+        assert!(is_multicast(&destination(&frame).unwrap()));
+        assert!(!is_multicast(&src));
+        assert_eq!(destination(&frame[..13]), None);
+
+        let mut macs = Macs::default();
+        assert!(macs.learn(src));
+        assert!(!macs.learn(src));
+        assert!(!macs.learn([0xff; 6]));
+        assert!(macs.contains(&src));
+        for i in 0..MAX_MACS {
+            macs.learn([0x02, 0, 0, 0, 1, i as u8]);
+        }
+        assert!(!macs.contains(&src));
+        macs.forget(&[0x02, 0, 0, 0, 1, 0]);
+        assert!(!macs.contains(&[0x02, 0, 0, 0, 1, 0]));
+    }
+}
@@ -12,14 +12,52 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::journal;
 use libc;
 use log::info;
+use serde_derive::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::BufRead;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::RawFd;
 use std::process::Command;
+use std::str::FromStr;
+use std::{io, mem, ptr};
+use zeroize::Zeroizing;
 
 pub fn is_root() -> bool {
     unsafe { libc::geteuid() == 0 }
 }
 
+pub fn stdin_is_tty() -> bool {
+    unsafe { libc::isatty(libc::STDIN_FILENO) == 1 }
+}
+
+/// Reads a line from stdin without echoing it.
+pub fn prompt_password(prompt: &str) -> Result<Zeroizing<String>, String> {
+    eprint!("{}", prompt);
+    let fd = libc::STDIN_FILENO;
+    let mut term: libc::termios = unsafe { mem::zeroed() };
+    let is_tty = unsafe { libc::tcgetattr(fd, &mut term) } == 0;
+    if is_tty {
+        let mut silent = term;
+        silent.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &silent) };
+    }
+    // Reserve up front so that the buffer is never reallocated and copied.
+    let mut line = Zeroizing::new(String::with_capacity(1024));
+    let result = io::stdin().lock().read_line(&mut line);
+    if is_tty {
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &term) };
+        eprintln!();
+    }
+    result.map_err(|e| e.to_string())?;
+    let len = line.trim_end_matches(['\r', '\n']).len();
+    line.truncate(len);
+    Ok(line)
+}
+
 pub fn enable_ipv4_forwarding() -> Result<(), String> {
     let sysctl_arg = if cfg!(target_os = "linux") {
         "net.ipv4.ip_forward=1"
@@ -46,37 +84,323 @@ pub enum RouteType {
     Host,
 }
 
+/// An IPv4 network in CIDR notation, e.g. 192.168.0.0/16.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Ipv4Net {
+    addr: Ipv4Addr,
+    prefix: u8,
+}
+
+impl Ipv4Net {
+    pub fn new(addr: Ipv4Addr, prefix: u8) -> Result<Ipv4Net, String> {
+        if prefix > 32 {
+            return Err(format!("invalid prefix length: {}", prefix));
+        }
+        let net = Ipv4Net {
+            addr,
+            prefix,
+        };
+        if u32::from(addr) & !net.mask() != 0 {
+            return Err(format!("{}/{} has host bits set", addr, prefix));
+        }
+        Ok(net)
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    fn mask(&self) -> u32 {
+        if self.prefix == 0 {
+            0
+        } else {
+            !0 << (32 - self.prefix)
+        }
+    }
+
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        u32::from(ip) & self.mask() == u32::from(self.addr)
+    }
+
+    /// Whether `other` lies entirely within this network.
+    pub fn includes(&self, other: &Ipv4Net) -> bool {
+        self.prefix <= other.prefix && self.contains(other.addr)
+    }
+
+    pub fn overlaps(&self, other: &Ipv4Net) -> bool {
+        self.includes(other) || other.includes(self)
+    }
+}
+
+impl FromStr for Ipv4Net {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Ipv4Net, String> {
+        let (addr, prefix) = match s.find('/') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => (s, "32"),
+        };
+        let addr = addr
+            .parse::<Ipv4Addr>()
+            .map_err(|e| format!("{}: {}", s, e))?;
+        let prefix = prefix
+            .parse::<u8>()
+            .map_err(|e| format!("{}: {}", s, e))?;
+        Ipv4Net::new(addr, prefix)
+    }
+}
+
+impl TryFrom<String> for Ipv4Net {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Ipv4Net, String> {
+        s.parse()
+    }
+}
+
+impl From<Ipv4Net> for String {
+    fn from(net: Ipv4Net) -> String {
+        net.to_string()
+    }
+}
+
+impl fmt::Display for Ipv4Net {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Routes pushed from the server to its clients.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct Routing {
+    pub routes: Vec<Ipv4Net>,
+    /// Overrides whether the client routes all traffic through the tunnel.
+    pub default_route: Option<bool>,
+}
+
+/// The host route to the server and the networks that bypass the tunnel, all
+/// through the original default gateway, and optionally the default route
+/// through the tunnel. Everything is restored when dropped.
 pub struct DefaultGateway {
-    origin: String,
-    remote: String,
-    default: bool,
+    _changes: journal::Changes,
 }
 
 impl DefaultGateway {
-    pub fn create(gateway: &str, remote: &str, default: bool) -> DefaultGateway {
-        let origin = get_default_gateway().unwrap();
+    pub fn create(
+        gateway: &str,
+        remote: &str,
+        default: bool,
+        exclude: &[Ipv4Net],
+    ) -> Result<DefaultGateway, String> {
+        let origin = get_default_gateway()?;
         info!("Original default gateway: {}.", origin);
-        add_route(RouteType::Host, remote, &origin).unwrap();
+        let mut changes = journal::Changes::default();
+        add_route(RouteType::Host, remote, &origin)?;
+        changes.push(journal::Change::HostRoute {
+            host: String::from(remote),
+        });
+        for net in exclude {
+            add_route(RouteType::Net, &net.to_string(), &origin)?;
+            changes.push(journal::Change::Route {
+                net: net.to_string(),
+            });
+        }
         if default {
-            delete_default_gateway().unwrap();
-            set_default_gateway(gateway).unwrap();
+            delete_default_gateway()?;
+            changes.push(journal::Change::DefaultRoute {
+                origin: origin.clone(),
+            });
+            set_default_gateway(gateway)?;
         }
-        DefaultGateway {
-            origin: origin,
-            remote: String::from(remote),
-            default: default,
+        Ok(DefaultGateway { _changes: changes })
+    }
+}
+
+/// Routes through the tunnel that are removed again when dropped.
+pub struct Routes {
+    _changes: journal::Changes,
+}
+
+impl Routes {
+    pub fn create(gateway: &str, routes: &[Ipv4Net]) -> Result<Routes, String> {
+        let mut changes = journal::Changes::default();
+        for route in routes {
+            add_route(RouteType::Net, &route.to_string(), gateway)?;
+            changes.push(journal::Change::Route {
+                net: route.to_string(),
+            });
         }
+        Ok(Routes { _changes: changes })
     }
 }
 
-impl Drop for DefaultGateway {
-    fn drop(&mut self) {
-        if self.default {
-            delete_default_gateway().unwrap();
-            set_default_gateway(&self.origin).unwrap();
+/// A route to one client whose MTU is lower than the TUN device's, so that the
+/// kernel fragments packets for it or tells their senders to send less.
+/// Removed when dropped. Linux only.
+pub struct MtuRoute {
+    _changes: journal::Changes,
+}
+
+impl MtuRoute {
+    pub fn create(if_name: &str, host: &str, mtu: u32) -> Result<MtuRoute, String> {
+        ip_route(&["add", host, "dev", if_name, "mtu", &mtu.to_string()])?;
+        let mut changes = journal::Changes::default();
+        changes.push(journal::Change::HostRoute {
+            host: String::from(host),
+        });
+        Ok(MtuRoute { _changes: changes })
+    }
+}
+
+/// Routes everything but kytan's own packets, which carry `mark`, through the
+/// tunnel using a routing table of its own and `ip rule`s, like wg-quick. The
+/// main table is left untouched, so changes of the default gateway (e.g. when
+/// roaming between networks) keep working. TAP devices need a `gateway`.
+/// Linux only.
+pub struct PolicyRouting {
+    /// The table and the rules added, undone in reverse order.
+    changes: journal::Changes,
+}
+
+impl PolicyRouting {
+    pub fn create(
+        if_name: &str,
+        gateway: Option<&str>,
+        mark: u32,
+        exclude: &[Ipv4Net],
+    ) -> Result<PolicyRouting, String> {
+        let table = mark.to_string();
+        let mut route = vec!["add", "default"];
+        if let Some(gateway) = gateway {
+            route.extend(&["via", gateway]);
         }
-        delete_route(RouteType::Host, &self.remote).unwrap();
+        route.extend(&["dev", if_name, "table", &table]);
+        ip_route(&route)?;
+        let mut policy = PolicyRouting {
+            changes: journal::Changes::default(),
+        };
+        policy.changes.push(journal::Change::Table {
+            table: table.clone(),
+        });
+        policy.add_rule(&["not", "fwmark", &table, "table", &table])?;
+        // More specific routes of the main table, e.g. to the LAN, still apply.
+        policy.add_rule(&["table", "main", "suppress_prefixlength", "0"])?;
+        for net in exclude {
+            policy.add_rule(&["to", &net.to_string(), "table", "main"])?;
+        }
+        Ok(policy)
+    }
+
+    /// Rules added later take precedence.
+    fn add_rule(&mut self, args: &[&str]) -> Result<(), String> {
+        ip_rule("add", args)?;
+        self.changes.push(journal::Change::Rule {
+            args: args.iter().map(|arg| String::from(*arg)).collect(),
+        });
+        Ok(())
+    }
+}
+
+pub fn ip_rule(action: &str, args: &[&str]) -> Result<(), String> {
+    info!("Running ip rule {} {}.", action, args.join(" "));
+    let status = Command::new("ip")
+        .arg("-4")
+        .arg("rule")
+        .arg(action)
+        .args(args)
+        .status()
+        .map_err(|e| format!("ip: {}", e))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("ip rule: {}", status))
+    }
+}
+
+pub fn ip_route(args: &[&str]) -> Result<(), String> {
+    info!("Running ip route {}.", args.join(" "));
+    let status = Command::new("ip")
+        .arg("-4")
+        .arg("route")
+        .args(args)
+        .status()
+        .map_err(|e| format!("ip: {}", e))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("ip route: {}", status))
+    }
+}
+
+/// Whether `ip route show` lists a route for `args`. Assumed elsewhere than
+/// on Linux.
+pub fn route_exists(args: &[&str]) -> bool {
+    if !cfg!(target_os = "linux") {
+        return true;
     }
+    Command::new("ip")
+        .args(["-4", "route", "show"])
+        .args(args)
+        .output()
+        .map(|output| !output.stdout.is_empty())
+        .unwrap_or(true)
+}
+
+/// Marks the packets sent through `fd` with SO_MARK, so that they bypass the
+/// rules of `PolicyRouting`.
+#[cfg(target_os = "linux")]
+pub fn set_mark(fd: RawFd, mark: u32) -> Result<(), String> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_MARK,
+            &mark as *const u32 as *const libc::c_void,
+            mem::size_of::<u32>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(format!("SO_MARK: {}", io::Error::last_os_error()))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_mark(_fd: RawFd, _mark: u32) -> Result<(), String> {
+    Err(String::from("SO_MARK is only supported on Linux"))
+}
+
+/// Sends the packets of `fd` with the Don't Fragment bit and regardless of
+/// the path MTU known to the kernel while `probe` is set, so that probes that
+/// are too big get lost instead of fragmented.
+#[cfg(target_os = "linux")]
+pub fn set_probing(fd: RawFd, probe: bool) -> Result<(), String> {
+    let mode = if probe {
+        libc::IP_PMTUDISC_PROBE
+    } else {
+        libc::IP_PMTUDISC_WANT
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            &mode as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(format!("IP_MTU_DISCOVER: {}", io::Error::last_os_error()))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_probing(_fd: RawFd, _probe: bool) -> Result<(), String> {
+    Err(String::from("Path MTU discovery is only supported on Linux"))
 }
 
 pub fn delete_route(route_type: RouteType, route: &str) -> Result<(), String> {
@@ -173,18 +497,59 @@ pub fn get_default_gateway() -> Result<String, String> {
     }
 }
 
-pub fn get_public_ip() -> Result<String, String> {
-    let output = Command::new("curl")
-        .arg("ipecho.net/plain")
-        .output()
-        .unwrap();
-    if output.status.success() {
-        Ok(String::from_utf8(output.stdout).unwrap())
-    } else {
-        Err(String::from_utf8(output.stderr).unwrap())
+pub fn get_interface_addresses() -> Result<Vec<IpAddr>, String> {
+    let mut addrs = Vec::new();
+    let mut ifaddrs: *mut libc::ifaddrs = ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(format!("getifaddrs: {}", io::Error::last_os_error()));
+    }
+    let mut cur = ifaddrs;
+    while !cur.is_null() {
+        let ifa = unsafe { &*cur };
+        if !ifa.ifa_addr.is_null() {
+            match unsafe { (*ifa.ifa_addr).sa_family } as libc::c_int {
+                libc::AF_INET => {
+                    let sin = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                    addrs.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr))));
+                }
+                libc::AF_INET6 => {
+                    let sin6 = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+                    addrs.push(IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr)));
+                }
+                _ => {}
+            }
+        }
+        cur = ifa.ifa_next;
+    }
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    Ok(addrs)
+}
+
+pub fn is_global(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            let shared = octets[0] == 100 && (octets[1] & 0xc0) == 64;
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || shared)
+        }
+        IpAddr::V6(ip) => (ip.segments()[0] & 0xe000) == 0x2000,
     }
 }
 
+/// Returns the addresses clients can reach this host at, without leaving the host.
+pub fn get_public_ips() -> Result<Vec<IpAddr>, String> {
+    Ok(get_interface_addresses()?
+        .into_iter()
+        .filter(is_global)
+        .collect())
+}
+
 fn get_route_gateway(route: &str) -> Result<String, String> {
     let cmd = format!("ip -4 route list {}", route);
     let output = Command::new("bash").arg("-c").arg(cmd).output().unwrap();
@@ -198,16 +563,6 @@ fn get_route_gateway(route: &str) -> Result<String, String> {
     }
 }
 
-pub fn set_dns(dns: &str) -> Result<String, String> {
-    let cmd = format!("echo nameserver {} > /etc/resolv.conf", dns);
-    let output = Command::new("bash").arg("-c").arg(cmd).output().unwrap();
-    if output.status.success() {
-        Ok(String::from_utf8(output.stdout).unwrap())
-    } else {
-        Err(String::from_utf8(output.stderr).unwrap())
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use crate::utils::*;
@@ -216,6 +571,41 @@ mod tests {
     fn enable_ipv4_forwarding_test() {
         enable_ipv4_forwarding().unwrap();
     }
+    #[test]
+    fn get_interface_addresses_test() {
+        let addrs = get_interface_addresses().unwrap();
+        assert!(addrs.contains(&IpAddr::V4(Ipv4Addr::LOCALHOST)));
+    }
+
+    #[test]
+    fn is_global_test() {
+        assert!(is_global(&"1.1.1.1".parse().unwrap()));
+        assert!(is_global(&"2001:4860:4860::8888".parse().unwrap()));
+        assert!(!is_global(&"10.10.10.1".parse().unwrap()));
+        assert!(!is_global(&"100.64.0.1".parse().unwrap()));
+        assert!(!is_global(&"127.0.0.1".parse().unwrap()));
+        assert!(!is_global(&"fe80::1".parse().unwrap()));
+        assert!(!is_global(&"fd00::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ipv4_net_test() {
+        let net: Ipv4Net = "192.168.0.0/16".parse().unwrap();
+        assert_eq!(net.to_string(), "192.168.0.0/16");
+        assert_eq!("1.1.1.1".parse::<Ipv4Net>().unwrap().to_string(), "1.1.1.1/32");
+        assert_eq!("0.0.0.0/0".parse::<Ipv4Net>().unwrap().mask(), 0);
+        assert!(net.contains(Ipv4Addr::new(192, 168, 3, 4)));
+        assert!(!net.contains(Ipv4Addr::new(192, 169, 0, 0)));
+        let lan: Ipv4Net = "192.168.3.0/24".parse().unwrap();
+        assert!(net.includes(&lan));
+        assert!(!lan.includes(&net));
+        assert!(lan.overlaps(&net));
+        assert!(!lan.overlaps(&"192.168.4.0/24".parse().unwrap()));
+        assert!("192.168.1.1/16".parse::<Ipv4Net>().is_err());
+        assert!("192.168.0.0/33".parse::<Ipv4Net>().is_err());
+        assert!("example.com/8".parse::<Ipv4Net>().is_err());
+    }
+
     #[test]
     #[cfg(target_os = "linux")]
     fn get_default_gateway_test() {
@@ -233,9 +623,22 @@ mod tests {
         delete_route(RouteType::Host, "1.1.1.1").unwrap();
         assert!(!get_route_gateway("1.1.1.1").unwrap().contains(&*gw));
     }
+
     #[test]
-    fn set_dns_test() {
+    #[cfg(target_os = "linux")]
+    fn policy_routing_test() {
         assert!(is_root());
-        set_dns("8.8.8.8").unwrap();
+        let rules = || {
+            let output = Command::new("ip").args(["-4", "rule"]).output().unwrap();
+            String::from_utf8(output.stdout).unwrap()
+        };
+        let exclude = ["1.1.1.0/24".parse().unwrap()];
+        let policy = PolicyRouting::create("lo", None, 51821, &exclude).unwrap();
+        assert!(get_route_gateway("default table 51821").unwrap().contains("dev lo"));
+        assert!(rules().contains("not from all fwmark 0xca6d lookup 51821"));
+        assert!(rules().contains("from all to 1.1.1.0/24 lookup main"));
+        drop(policy);
+        assert!(!rules().contains("51821"));
+        assert!(get_route_gateway("table 51821").unwrap().is_empty());
     }
 }